@@ -0,0 +1,917 @@
+//! A minimal init for images without an init system, installed by `run --init`.
+//! It is meant to be built statically, e.g. with `cargo build --release --target x86_64-unknown-linux-musl`.
+//! It mounts the kernel file systems and the shared folders in /etc/fstab, configures networking with DHCP, sets the
+//! hostname, grows the root file system to the size of the disk and runs the command from
+//! /etc/docker-on-kvm/init.json. When the command exits, the VM is powered off and the exit code is reported.
+//! For read-only root file systems, it first mounts the writable layer over the root using overlayfs, after which it
+//! either continues as above or hands over to the init given by docker-on-kvm.init on the kernel command line.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+const CONFIG_PATH: &str = "/etc/docker-on-kvm/init.json";
+const EXIT_CODE_PATH: &str = "/etc/docker-on-kvm/exit-code";
+const MODULES_PATH: &str = "/etc/docker-on-kvm/modules";
+/// Where the writable layer is mounted, which exists in read-only images.
+const ROOT_LAYER_PATH: &str = "/.docker-on-kvm";
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Written by docker-on-kvm, see entrypoint::InitConfig.
+#[derive(Debug, Default, Deserialize)]
+struct InitConfig {
+    command: Vec<String>,
+    #[serde(default)]
+    environment: Vec<String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    working_directory: Option<String>,
+    #[serde(default)]
+    hostname: String
+}
+
+fn main() {
+    if std::process::id() != 1 {
+        eprintln!("docker-on-kvm-init must be run as the init process");
+        std::process::exit(1);
+    }
+
+    match root_overlay::setup() {
+        Ok(Some(next_init)) => {
+            let err = std::process::Command::new(&next_init).exec();
+            log(&format!("failed to start {}: {}", next_init, err));
+            power_off();
+        }
+        Ok(None) => {}
+        Err(err) => {
+            log(&err);
+            power_off();
+        }
+    }
+
+    let exit_code = match run() {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            log(&err);
+            1
+        }
+    };
+
+    log(&format!("command exited with code {}", exit_code));
+    if let Err(err) = std::fs::write(EXIT_CODE_PATH, format!("{}\n", exit_code)) {
+        log(&format!("failed to write the exit code: {}", err));
+    }
+
+    power_off();
+}
+
+fn log(message: &str) {
+    eprintln!("docker-on-kvm-init: {}", message);
+}
+
+fn run() -> Result<i32, String> {
+    mount_file_systems()?;
+
+    // The exit code of the previous boot would otherwise be reported if this one doesn't get to write one
+    let _ = std::fs::remove_file(EXIT_CODE_PATH);
+
+    // The disk might have been grown since the last boot
+    if let Err(err) = grow_root_file_system() {
+        log(&err);
+    }
+
+    let config = std::fs::read_to_string(CONFIG_PATH).map_err(|err| format!("failed to read {}: {}", CONFIG_PATH, err))?;
+    let config = serde_json::from_str::<InitConfig>(&config).map_err(|err| format!("failed to parse {}: {}", CONFIG_PATH, err))?;
+
+    if !config.hostname.is_empty()
+        && unsafe { libc::sethostname(config.hostname.as_ptr() as *const libc::c_char, config.hostname.len()) } != 0 {
+        log(&format!("failed to set the hostname: {}", std::io::Error::last_os_error()));
+    }
+
+    load_kernel_modules();
+    mount_fstab();
+
+    // The command might not need the network, so it is started regardless
+    if let Err(err) = network::configure() {
+        log(&format!("failed to configure the network: {}", err));
+    }
+
+    let child_pid = spawn_command(&config)?;
+    wait_for(child_pid)
+}
+
+fn mount_file_systems() -> Result<(), String> {
+    mount("proc", "/proc", "proc", 0, "")?;
+    mount("sysfs", "/sys", "sysfs", 0, "")?;
+    mount("devtmpfs", "/dev", "devtmpfs", 0, "mode=0755")?;
+    mount("devpts", "/dev/pts", "devpts", 0, "mode=0620,ptmxmode=0666")?;
+    mount("tmpfs", "/dev/shm", "tmpfs", 0, "mode=1777")?;
+    mount("tmpfs", "/run", "tmpfs", 0, "mode=0755")?;
+    Ok(())
+}
+
+fn grow_root_file_system() -> Result<(), String> {
+    let command_line = std::fs::read_to_string("/proc/cmdline").map_err(|err| format!("failed to read /proc/cmdline: {}", err))?;
+    match (kernel_parameter(&command_line, "root"), kernel_parameter(&command_line, "rootfstype")) {
+        (Some(device), Some(file_system)) => grow_fs::grow(&device, "/", &file_system),
+        _ => Ok(())
+    }
+}
+
+fn kernel_parameter(command_line: &str, name: &str) -> Option<String> {
+    command_line
+        .split_whitespace()
+        .find_map(|parameter| parameter.strip_prefix(name)?.strip_prefix('='))
+        .map(|value| value.to_owned())
+}
+
+/// Loads the host kernel modules copied to the image, which are listed in the order to load them.
+fn load_kernel_modules() {
+    const MODULE_INIT_COMPRESSED_FILE: libc::c_uint = 4;
+
+    let modules = match std::fs::read_to_string(MODULES_PATH) {
+        Ok(modules) => modules,
+        Err(_) => return
+    };
+
+    for module in modules.lines().filter(|module| !module.is_empty()) {
+        let file = match std::fs::File::open(module) {
+            Ok(file) => file,
+            Err(err) => {
+                log(&format!("failed to open the kernel module {}: {}", module, err));
+                continue;
+            }
+        };
+
+        let compressed = [".zst", ".xz", ".gz"].iter().any(|extension| module.ends_with(extension));
+        let flags = if compressed { MODULE_INIT_COMPRESSED_FILE } else { 0 };
+        let params = CString::new("").unwrap();
+
+        let result = unsafe { libc::syscall(libc::SYS_finit_module, file.as_raw_fd(), params.as_ptr(), flags) };
+        if result != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EEXIST) {
+                log(&format!("failed to load the kernel module {}: {}", module, err));
+            }
+        }
+    }
+}
+
+/// Mounts the entries of /etc/fstab other than the root file system and swap, like the shared folders.
+fn mount_fstab() {
+    let fstab = std::fs::read_to_string("/etc/fstab").unwrap_or_default();
+    for line in fstab.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if parts.len() < 4 || parts[1] == "/" || parts[2] == "swap" {
+            continue;
+        }
+
+        if parts[3].split(',').any(|option| option == "noauto") {
+            continue;
+        }
+
+        let mut flags = 0;
+        let mut options = Vec::new();
+        for option in parts[3].split(',') {
+            match option {
+                "ro" => flags |= libc::MS_RDONLY,
+                "defaults" | "rw" | "nofail" | "auto" => {}
+                option => options.push(option)
+            }
+        }
+
+        let mount_point = parts[1].replace("\\040", " ");
+        if let Err(err) = mount(parts[0], &mount_point, parts[2], flags, &options.join(",")) {
+            log(&err);
+        }
+    }
+}
+
+fn mount(source: &str, target: &str, file_system: &str, flags: libc::c_ulong, data: &str) -> Result<(), String> {
+    std::fs::create_dir_all(target).map_err(|err| format!("failed to create {}: {}", target, err))?;
+
+    let c_source = CString::new(source).unwrap();
+    let c_target = CString::new(target).unwrap();
+    let c_file_system = CString::new(file_system).unwrap();
+    let c_data = CString::new(data).unwrap();
+
+    let result = unsafe {
+        libc::mount(c_source.as_ptr(), c_target.as_ptr(), c_file_system.as_ptr(), flags, c_data.as_ptr() as *const libc::c_void)
+    };
+
+    if result != 0 {
+        let err = std::io::Error::last_os_error();
+        // Already mounted, e.g. /dev by the kernel
+        if err.raw_os_error() != Some(libc::EBUSY) {
+            return Err(format!("failed to mount {}: {}", target, err));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+struct User {
+    uid: u32,
+    gid: u32,
+    home: Option<String>
+}
+
+/// Resolves a docker user specification (user, uid, user:group or uid:gid) using the passwd and group files.
+fn resolve_user(user: &str) -> Result<User, String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let groups = std::fs::read_to_string("/etc/group").unwrap_or_default();
+    parse_user(user, &passwd, &groups)
+}
+
+fn parse_user(user: &str, passwd: &str, groups: &str) -> Result<User, String> {
+    let (user, group) = match user.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (user, None)
+    };
+
+    let passwd_entry = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 6 && (fields[0] == user || fields[2] == user));
+
+    let mut resolved = match (passwd_entry, user.parse::<u32>()) {
+        (Some(fields), _) => User {
+            uid: fields[2].parse().map_err(|_| format!("invalid uid for user {}", user))?,
+            gid: fields[3].parse().map_err(|_| format!("invalid gid for user {}", user))?,
+            home: Some(fields[5].to_owned())
+        },
+        (None, Ok(uid)) => User { uid, gid: uid, home: None },
+        (None, Err(_)) => return Err(format!("unknown user {}", user))
+    };
+
+    if let Some(group) = group {
+        resolved.gid = groups
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() >= 3 && fields[0] == group)
+            .and_then(|fields| fields[2].parse().ok())
+            .or_else(|| group.parse().ok())
+            .ok_or_else(|| format!("unknown group {}", group))?;
+    }
+
+    Ok(resolved)
+}
+
+fn spawn_command(config: &InitConfig) -> Result<libc::pid_t, String> {
+    if config.command.is_empty() {
+        return Err("no command to run".to_owned());
+    }
+
+    let mut environment = config.environment
+        .iter()
+        .map(|variable| {
+            match variable.split_once('=') {
+                Some((name, value)) => (name.to_owned(), value.to_owned()),
+                None => (variable.clone(), String::new())
+            }
+        })
+        .collect::<HashMap<_, _>>();
+
+    environment.entry("PATH".to_owned()).or_insert_with(|| DEFAULT_PATH.to_owned());
+    environment.entry("HOSTNAME".to_owned()).or_insert_with(|| config.hostname.clone());
+
+    let mut command = std::process::Command::new(&config.command[0]);
+    command.args(&config.command[1..]);
+
+    match &config.user {
+        Some(user) => {
+            let user = resolve_user(user)?;
+            command.uid(user.uid).gid(user.gid);
+            environment.entry("HOME".to_owned()).or_insert_with(|| user.home.unwrap_or_else(|| "/".to_owned()));
+        }
+        None => {
+            environment.entry("HOME".to_owned()).or_insert_with(|| "/root".to_owned());
+        }
+    }
+
+    command.env_clear().envs(&environment);
+    command.current_dir(config.working_directory.as_deref().unwrap_or("/"));
+
+    let child = command.spawn().map_err(|err| format!("failed to start {}: {}", config.command[0], err))?;
+    Ok(child.id() as libc::pid_t)
+}
+
+/// Waits for the command to exit while reaping any other process that ends up as a child of init.
+fn wait_for(child_pid: libc::pid_t) -> Result<i32, String> {
+    loop {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if pid == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(format!("failed to wait for the command: {}", err));
+        }
+
+        if pid == child_pid {
+            if libc::WIFEXITED(status) {
+                return Ok(libc::WEXITSTATUS(status));
+            } else if libc::WIFSIGNALED(status) {
+                return Ok(128 + libc::WTERMSIG(status));
+            }
+        }
+    }
+}
+
+fn power_off() -> ! {
+    unsafe {
+        libc::kill(-1, libc::SIGTERM);
+        std::thread::sleep(Duration::from_secs(1));
+        libc::kill(-1, libc::SIGKILL);
+
+        libc::sync();
+        libc::reboot(libc::LINUX_REBOOT_CMD_POWER_OFF);
+    }
+
+    // Only reached if the power off failed, and init must never exit
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
+mod root_overlay {
+    use super::*;
+
+    /// Mounts the writable layer given by docker-on-kvm.overlay over the read-only root and makes the result the new
+    /// root. Returns the init to hand over to, if any.
+    pub fn setup() -> Result<Option<String>, String> {
+        mount("proc", "/proc", "proc", 0, "")?;
+        let command_line = std::fs::read_to_string("/proc/cmdline").map_err(|err| format!("failed to read /proc/cmdline: {}", err))?;
+        unmount("/proc", 0)?;
+
+        let overlay_device = match kernel_parameter(&command_line, "docker-on-kvm.overlay") {
+            Some(overlay_device) => overlay_device,
+            None => return Ok(None)
+        };
+
+        // The kernel only mounts /dev by itself when configured to
+        if !Path::new(&overlay_device).exists() {
+            mount("devtmpfs", "/dev", "devtmpfs", 0, "mode=0755")?;
+        }
+
+        mount(&overlay_device, ROOT_LAYER_PATH, "ext4", 0, "")?;
+        if let Err(err) = grow_fs::grow(&overlay_device, ROOT_LAYER_PATH, "ext4") {
+            log(&err);
+        }
+
+        let layer_path = Path::new(ROOT_LAYER_PATH);
+        let (upper_path, work_path, root_path) = (layer_path.join("upper"), layer_path.join("work"), layer_path.join("root"));
+        for path in [&upper_path, &work_path, &root_path] {
+            std::fs::create_dir_all(path).map_err(|err| format!("failed to create {}: {}", path.display(), err))?;
+        }
+
+        let options = format!("lowerdir=/,upperdir={},workdir={}", upper_path.display(), work_path.display());
+        mount("overlay", root_path.to_str().unwrap(), "overlay", 0, &options)?;
+
+        // Stacks the old root on top of the new one, and then detaches it
+        std::env::set_current_dir(&root_path).map_err(|err| format!("failed to change directory: {}", err))?;
+        let current_dir = CString::new(".").unwrap();
+        if unsafe { libc::syscall(libc::SYS_pivot_root, current_dir.as_ptr(), current_dir.as_ptr()) } != 0 {
+            return Err(format!("failed to change the root: {}", std::io::Error::last_os_error()));
+        }
+        unmount(".", libc::MNT_DETACH)?;
+        std::env::set_current_dir("/").map_err(|err| format!("failed to change directory: {}", err))?;
+
+        Ok(kernel_parameter(&command_line, "docker-on-kvm.init"))
+    }
+
+    fn unmount(target: &str, flags: libc::c_int) -> Result<(), String> {
+        let c_target = CString::new(target).unwrap();
+        if unsafe { libc::umount2(c_target.as_ptr(), flags) } != 0 {
+            return Err(format!("failed to unmount {}: {}", target, std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+mod grow_fs {
+    use super::*;
+
+    const BLKGETSIZE64: libc::c_ulong = 0x80081272;
+    const EXT4_IOC_RESIZE_FS: libc::c_ulong = 0x40086610;
+    const XFS_IOC_FSGEOMETRY_V1: libc::c_ulong = 0x80705864;
+    const XFS_IOC_FSGROWFSDATA: libc::c_ulong = 0x4010586e;
+    const BTRFS_IOC_RESIZE: libc::c_ulong = 0x50009403;
+
+    /// struct xfs_fsop_geom_v1
+    #[repr(C)]
+    struct XfsGeometry {
+        blocksize: u32,
+        rtextsize: u32,
+        agblocks: u32,
+        agcount: u32,
+        logblocks: u32,
+        sectsize: u32,
+        inodesize: u32,
+        imaxpct: u32,
+        datablocks: u64,
+        rtblocks: u64,
+        rtextents: u64,
+        logstart: u64,
+        uuid: [u8; 16],
+        sunit: u32,
+        swidth: u32,
+        version: i32,
+        flags: u32,
+        logsectsize: u32,
+        rtsectsize: u32,
+        dirblocksize: u32
+    }
+
+    /// struct xfs_growfs_data
+    #[repr(C)]
+    struct XfsGrowData {
+        newblocks: u64,
+        imaxpct: u32
+    }
+
+    /// struct btrfs_ioctl_vol_args
+    #[repr(C)]
+    struct BtrfsVolumeArgs {
+        fd: i64,
+        name: [u8; 4088]
+    }
+
+    /// Grows the file system on the device mounted at the mount point to the size of the device, using the same
+    /// ioctls as resize2fs, xfs_growfs and btrfs, which images rarely contain. Does nothing if it already fills it.
+    pub fn grow(device: &str, mount_point: &str, file_system: &str) -> Result<(), String> {
+        let mount_dir = std::fs::File::open(mount_point).map_err(|err| format!("failed to open {}: {}", mount_point, err))?;
+        let fd = mount_dir.as_raw_fd();
+
+        match file_system {
+            "ext4" => {
+                let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+                if unsafe { libc::fstatvfs(fd, &mut stat) } != 0 {
+                    return Err(format!("failed to get the block size of {}: {}", mount_point, std::io::Error::last_os_error()));
+                }
+
+                let mut blocks = device_size(device)? / stat.f_bsize as u64;
+                ioctl(fd, EXT4_IOC_RESIZE_FS, &mut blocks as *mut _ as *mut libc::c_void, mount_point)
+            }
+            "xfs" => {
+                let mut geometry = unsafe { std::mem::zeroed::<XfsGeometry>() };
+                ioctl(fd, XFS_IOC_FSGEOMETRY_V1, &mut geometry as *mut _ as *mut libc::c_void, mount_point)?;
+
+                let blocks = device_size(device)? / geometry.blocksize as u64;
+                if blocks <= geometry.datablocks {
+                    return Ok(());
+                }
+
+                let mut grow_data = XfsGrowData { newblocks: blocks, imaxpct: geometry.imaxpct };
+                ioctl(fd, XFS_IOC_FSGROWFSDATA, &mut grow_data as *mut _ as *mut libc::c_void, mount_point)
+            }
+            "btrfs" => {
+                let mut args = BtrfsVolumeArgs { fd: 0, name: [0; 4088] };
+                args.name[..3].copy_from_slice(b"max");
+                ioctl(fd, BTRFS_IOC_RESIZE, &mut args as *mut _ as *mut libc::c_void, mount_point)
+            }
+            _ => Ok(())
+        }
+    }
+
+    fn device_size(device: &str) -> Result<u64, String> {
+        let device_file = std::fs::File::open(device).map_err(|err| format!("failed to open {}: {}", device, err))?;
+        let mut size = 0u64;
+        if unsafe { libc::ioctl(device_file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } != 0 {
+            return Err(format!("failed to get the size of {}: {}", device, std::io::Error::last_os_error()));
+        }
+
+        Ok(size)
+    }
+
+    fn ioctl(fd: libc::c_int, request: libc::c_ulong, argument: *mut libc::c_void, mount_point: &str) -> Result<(), String> {
+        if unsafe { libc::ioctl(fd, request as _, argument) } != 0 {
+            return Err(format!("failed to grow the file system at {}: {}", mount_point, std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+mod network {
+    use super::*;
+
+    struct Lease {
+        address: Ipv4Addr,
+        netmask: Ipv4Addr,
+        router: Option<Ipv4Addr>,
+        dns_servers: Vec<Ipv4Addr>
+    }
+
+    /// Brings up the loopback interface and the first network interface, which is configured using DHCP.
+    pub fn configure() -> Result<(), String> {
+        let control_socket = UdpSocket::bind("0.0.0.0:0").map_err(|err| format!("failed to create socket: {}", err))?;
+        let control_fd = control_socket.as_raw_fd();
+
+        set_address(control_fd, "lo", Ipv4Addr::LOCALHOST, Ipv4Addr::new(255, 0, 0, 0))?;
+        set_up(control_fd, "lo")?;
+
+        let mut interfaces = std::fs::read_dir("/sys/class/net")
+            .map_err(|err| format!("failed to list network interfaces: {}", err))?
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|interface| interface != "lo")
+            .collect::<Vec<_>>();
+        interfaces.sort();
+
+        let interface = match interfaces.first() {
+            Some(interface) => interface,
+            None => return Ok(())
+        };
+
+        set_up(control_fd, interface)?;
+        let lease = dhcp::request(interface)?;
+        log(&format!("got address {} on {}", lease.address, interface));
+
+        set_address(control_fd, interface, lease.address, lease.netmask)?;
+        if let Some(router) = lease.router {
+            add_default_route(control_fd, interface, router)?;
+        }
+
+        if !lease.dns_servers.is_empty() {
+            // The extracted images link /etc/resolv.conf to where systemd-resolved keeps it
+            let _ = std::fs::create_dir_all("/run/systemd/resolve");
+            let resolv_conf = lease.dns_servers
+                .iter()
+                .map(|dns_server| format!("nameserver {}\n", dns_server))
+                .collect::<String>();
+            std::fs::write("/etc/resolv.conf", resolv_conf).map_err(|err| format!("failed to write /etc/resolv.conf: {}", err))?;
+        }
+
+        Ok(())
+    }
+
+    fn interface_request(interface: &str) -> libc::ifreq {
+        let mut request = unsafe { std::mem::zeroed::<libc::ifreq>() };
+        for (name_char, byte) in request.ifr_name.iter_mut().zip(interface.bytes().take(libc::IFNAMSIZ - 1)) {
+            *name_char = byte as libc::c_char;
+        }
+
+        request
+    }
+
+    fn socket_address(address: Ipv4Addr) -> libc::sockaddr {
+        let address = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: 0,
+            sin_addr: libc::in_addr { s_addr: u32::from(address).to_be() },
+            sin_zero: [0; 8]
+        };
+
+        unsafe { std::mem::transmute::<libc::sockaddr_in, libc::sockaddr>(address) }
+    }
+
+    fn ioctl(fd: libc::c_int, request: libc::c_ulong, argument: *mut libc::c_void, action: &str) -> Result<(), String> {
+        if unsafe { libc::ioctl(fd, request as _, argument) } != 0 {
+            return Err(format!("failed to {}: {}", action, std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    fn set_up(fd: libc::c_int, interface: &str) -> Result<(), String> {
+        let mut request = interface_request(interface);
+        ioctl(fd, libc::SIOCGIFFLAGS, &mut request as *mut _ as *mut libc::c_void, "get interface flags")?;
+        unsafe {
+            request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        }
+        ioctl(fd, libc::SIOCSIFFLAGS, &mut request as *mut _ as *mut libc::c_void, "bring up interface")
+    }
+
+    fn set_address(fd: libc::c_int, interface: &str, address: Ipv4Addr, netmask: Ipv4Addr) -> Result<(), String> {
+        let mut request = interface_request(interface);
+        request.ifr_ifru.ifru_addr = socket_address(address);
+        ioctl(fd, libc::SIOCSIFADDR, &mut request as *mut _ as *mut libc::c_void, "set address")?;
+
+        let mut request = interface_request(interface);
+        request.ifr_ifru.ifru_netmask = socket_address(netmask);
+        ioctl(fd, libc::SIOCSIFNETMASK, &mut request as *mut _ as *mut libc::c_void, "set netmask")
+    }
+
+    /// The routing table entry of SIOCADDRT, which libc only defines for some targets.
+    #[repr(C)]
+    struct RouteEntry {
+        rt_pad1: libc::c_ulong,
+        rt_dst: libc::sockaddr,
+        rt_gateway: libc::sockaddr,
+        rt_genmask: libc::sockaddr,
+        rt_flags: libc::c_ushort,
+        rt_pad2: libc::c_short,
+        rt_pad3: libc::c_ulong,
+        rt_pad4: *mut libc::c_void,
+        rt_metric: libc::c_short,
+        rt_dev: *mut libc::c_char,
+        rt_mtu: libc::c_ulong,
+        rt_window: libc::c_ulong,
+        rt_irtt: libc::c_ushort
+    }
+
+    fn add_default_route(fd: libc::c_int, interface: &str, router: Ipv4Addr) -> Result<(), String> {
+        let device = CString::new(interface).unwrap();
+        let mut route = RouteEntry {
+            rt_pad1: 0,
+            rt_dst: socket_address(Ipv4Addr::UNSPECIFIED),
+            rt_gateway: socket_address(router),
+            rt_genmask: socket_address(Ipv4Addr::UNSPECIFIED),
+            rt_flags: libc::RTF_UP | libc::RTF_GATEWAY,
+            rt_pad2: 0,
+            rt_pad3: 0,
+            rt_pad4: std::ptr::null_mut(),
+            rt_metric: 0,
+            rt_dev: device.as_ptr() as *mut libc::c_char,
+            rt_mtu: 0,
+            rt_window: 0,
+            rt_irtt: 0
+        };
+
+        ioctl(fd, libc::SIOCADDRT, &mut route as *mut _ as *mut libc::c_void, "add default route")
+    }
+
+    mod dhcp {
+        use super::*;
+
+        const MESSAGE_DISCOVER: u8 = 1;
+        const MESSAGE_OFFER: u8 = 2;
+        const MESSAGE_REQUEST: u8 = 3;
+        const MESSAGE_ACK: u8 = 5;
+        const MESSAGE_NAK: u8 = 6;
+
+        const OPTION_SUBNET_MASK: u8 = 1;
+        const OPTION_ROUTER: u8 = 3;
+        const OPTION_DNS_SERVERS: u8 = 6;
+        const OPTION_REQUESTED_ADDRESS: u8 = 50;
+        const OPTION_MESSAGE_TYPE: u8 = 53;
+        const OPTION_SERVER_ID: u8 = 54;
+        const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+        const OPTION_END: u8 = 255;
+
+        const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+        struct Reply {
+            message_type: u8,
+            address: Ipv4Addr,
+            options: HashMap<u8, Vec<u8>>
+        }
+
+        /// Gets a lease with a DHCP discover/offer/request/ack exchange.
+        pub fn request(interface: &str) -> Result<Lease, String> {
+            let mac_address = mac_address(interface)?;
+            let socket = UdpSocket::bind("0.0.0.0:68").map_err(|err| format!("failed to bind DHCP socket: {}", err))?;
+            socket.set_broadcast(true).map_err(|err| err.to_string())?;
+            socket.set_read_timeout(Some(Duration::from_secs(2))).map_err(|err| err.to_string())?;
+
+            // The interface has no address yet, so the socket must be bound to it to send and receive on it
+            let result = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    interface.as_ptr() as *const libc::c_void,
+                    interface.len() as libc::socklen_t
+                )
+            };
+            if result != 0 {
+                return Err(format!("failed to bind DHCP socket to {}: {}", interface, std::io::Error::last_os_error()));
+            }
+
+            for _ in 0..10 {
+                let transaction_id = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+
+                send(&socket, &packet(MESSAGE_DISCOVER, transaction_id, mac_address, &[]))?;
+                let offer = match receive(&socket, transaction_id, &[MESSAGE_OFFER]) {
+                    Some(offer) => offer,
+                    None => continue
+                };
+
+                let mut request_options = vec![(OPTION_REQUESTED_ADDRESS, offer.address.octets().to_vec())];
+                if let Some(server_id) = offer.options.get(&OPTION_SERVER_ID) {
+                    request_options.push((OPTION_SERVER_ID, server_id.clone()));
+                }
+
+                send(&socket, &packet(MESSAGE_REQUEST, transaction_id, mac_address, &request_options))?;
+                match receive(&socket, transaction_id, &[MESSAGE_ACK, MESSAGE_NAK]) {
+                    Some(ack) if ack.message_type == MESSAGE_ACK => return Ok(lease(&ack)),
+                    _ => continue
+                }
+            }
+
+            Err(format!("no DHCP lease on {}", interface))
+        }
+
+        fn mac_address(interface: &str) -> Result<[u8; 6], String> {
+            let text = std::fs::read_to_string(Path::new("/sys/class/net").join(interface).join("address"))
+                .map_err(|err| format!("failed to read MAC address: {}", err))?;
+
+            let mut mac_address = [0; 6];
+            for (byte, part) in mac_address.iter_mut().zip(text.trim().split(':')) {
+                *byte = u8::from_str_radix(part, 16).map_err(|_| format!("invalid MAC address '{}'", text.trim()))?;
+            }
+
+            Ok(mac_address)
+        }
+
+        fn packet(message_type: u8, transaction_id: u32, mac_address: [u8; 6], options: &[(u8, Vec<u8>)]) -> Vec<u8> {
+            let mut packet = vec![0; 236];
+            packet[0] = 1; // Boot request
+            packet[1] = 1; // Ethernet
+            packet[2] = 6; // Hardware address length
+            packet[4..8].copy_from_slice(&transaction_id.to_be_bytes());
+            packet[10..12].copy_from_slice(&0x8000u16.to_be_bytes()); // Ask for broadcast replies
+            packet[28..34].copy_from_slice(&mac_address);
+            packet.extend_from_slice(&MAGIC_COOKIE);
+
+            packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+            for (code, data) in options {
+                packet.push(*code);
+                packet.push(data.len() as u8);
+                packet.extend_from_slice(data);
+            }
+            packet.extend_from_slice(&[OPTION_PARAMETER_REQUEST_LIST, 3, OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVERS]);
+            packet.push(OPTION_END);
+            packet
+        }
+
+        fn send(socket: &UdpSocket, packet: &[u8]) -> Result<(), String> {
+            socket.send_to(packet, (Ipv4Addr::BROADCAST, 67)).map_err(|err| format!("failed to send DHCP message: {}", err))?;
+            Ok(())
+        }
+
+        /// Waits for a reply of one of the given types, returning None on timeout.
+        fn receive(socket: &UdpSocket, transaction_id: u32, message_types: &[u8]) -> Option<Reply> {
+            let mut buffer = [0; 1500];
+            loop {
+                let count = socket.recv(&mut buffer).ok()?;
+                if let Some(reply) = parse(&buffer[..count], transaction_id) {
+                    if message_types.contains(&reply.message_type) {
+                        return Some(reply);
+                    }
+                }
+            }
+        }
+
+        fn parse(packet: &[u8], transaction_id: u32) -> Option<Reply> {
+            if packet.len() < 240 || packet[0] != 2 || packet[4..8] != transaction_id.to_be_bytes() || packet[236..240] != MAGIC_COOKIE {
+                return None;
+            }
+
+            let mut options = HashMap::new();
+            let mut index = 240;
+            while index < packet.len() {
+                match packet[index] {
+                    0 => { index += 1; }
+                    OPTION_END => break,
+                    code => {
+                        let length = *packet.get(index + 1)? as usize;
+                        options.insert(code, packet.get(index + 2..index + 2 + length)?.to_vec());
+                        index += 2 + length;
+                    }
+                }
+            }
+
+            Some(Reply {
+                message_type: *options.get(&OPTION_MESSAGE_TYPE)?.first()?,
+                address: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+                options
+            })
+        }
+
+        fn lease(ack: &Reply) -> Lease {
+            let addresses = |code: u8| {
+                ack.options
+                    .get(&code)
+                    .map(|data| data.chunks_exact(4).map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])).collect::<Vec<_>>())
+                    .unwrap_or_default()
+            };
+
+            Lease {
+                address: ack.address,
+                netmask: addresses(OPTION_SUBNET_MASK).first().copied().unwrap_or(Ipv4Addr::new(255, 255, 255, 0)),
+                router: addresses(OPTION_ROUTER).first().copied(),
+                dns_servers: addresses(OPTION_DNS_SERVERS)
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            const MAC_ADDRESS: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+            /// A reply from the server to a packet sent by the client.
+            fn reply(transaction_id: u32, message_type: u8, address: Ipv4Addr, options: &[(u8, Vec<u8>)]) -> Vec<u8> {
+                let mut reply = packet(message_type, transaction_id, MAC_ADDRESS, options);
+                reply[0] = 2;
+                reply[16..20].copy_from_slice(&address.octets());
+                reply
+            }
+
+            #[test]
+            fn test_packet() {
+                let packet = packet(MESSAGE_REQUEST, 0x01020304, MAC_ADDRESS, &[(OPTION_REQUESTED_ADDRESS, vec![192, 168, 122, 10])]);
+                assert_eq!([1, 1, 6, 0], packet[0..4]);
+                assert_eq!([1, 2, 3, 4], packet[4..8]);
+                assert_eq!([0x80, 0], packet[10..12]);
+                assert_eq!(MAC_ADDRESS, packet[28..34]);
+                assert_eq!(MAGIC_COOKIE, packet[236..240]);
+                assert_eq!(
+                    [
+                        OPTION_MESSAGE_TYPE, 1, MESSAGE_REQUEST,
+                        OPTION_REQUESTED_ADDRESS, 4, 192, 168, 122, 10,
+                        OPTION_PARAMETER_REQUEST_LIST, 3, OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVERS,
+                        OPTION_END
+                    ],
+                    packet[240..]
+                );
+            }
+
+            #[test]
+            fn test_parse() {
+                let address = Ipv4Addr::new(192, 168, 122, 10);
+                let offer = reply(42, MESSAGE_OFFER, address, &[(OPTION_SERVER_ID, vec![192, 168, 122, 1])]);
+
+                let parsed = parse(&offer, 42).unwrap();
+                assert_eq!(MESSAGE_OFFER, parsed.message_type);
+                assert_eq!(address, parsed.address);
+                assert_eq!(Some(&vec![192, 168, 122, 1]), parsed.options.get(&OPTION_SERVER_ID));
+
+                // Other transactions, requests and truncated packets are ignored
+                assert!(parse(&offer, 43).is_none());
+                assert!(parse(&packet(MESSAGE_DISCOVER, 42, MAC_ADDRESS, &[]), 42).is_none());
+                assert!(parse(&offer[..239], 42).is_none());
+
+                let mut truncated_option = offer[..240].to_vec();
+                truncated_option.extend_from_slice(&[OPTION_ROUTER, 4, 192]);
+                assert!(parse(&truncated_option, 42).is_none());
+            }
+
+            #[test]
+            fn test_lease() {
+                let ack = reply(
+                    42,
+                    MESSAGE_ACK,
+                    Ipv4Addr::new(10, 0, 2, 15),
+                    &[
+                        (OPTION_SUBNET_MASK, vec![255, 255, 0, 0]),
+                        (OPTION_ROUTER, vec![10, 0, 2, 2]),
+                        (OPTION_DNS_SERVERS, vec![10, 0, 2, 3, 1, 1, 1, 1])
+                    ]
+                );
+
+                let dhcp_lease = lease(&parse(&ack, 42).unwrap());
+                assert_eq!(Ipv4Addr::new(10, 0, 2, 15), dhcp_lease.address);
+                assert_eq!(Ipv4Addr::new(255, 255, 0, 0), dhcp_lease.netmask);
+                assert_eq!(Some(Ipv4Addr::new(10, 0, 2, 2)), dhcp_lease.router);
+                assert_eq!(vec![Ipv4Addr::new(10, 0, 2, 3), Ipv4Addr::new(1, 1, 1, 1)], dhcp_lease.dns_servers);
+
+                let dhcp_lease = lease(&parse(&reply(42, MESSAGE_ACK, Ipv4Addr::new(10, 0, 2, 15), &[]), 42).unwrap());
+                assert_eq!(Ipv4Addr::new(255, 255, 255, 0), dhcp_lease.netmask);
+                assert_eq!(None, dhcp_lease.router);
+                assert!(dhcp_lease.dns_servers.is_empty());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_parameter() {
+        let command_line = "root=/dev/vda ro rootfstype=squashfs docker-on-kvm.overlay=/dev/vdb docker-on-kvm.init=/sbin/init quiet";
+        assert_eq!(Some("/dev/vda".to_owned()), kernel_parameter(command_line, "root"));
+        assert_eq!(Some("squashfs".to_owned()), kernel_parameter(command_line, "rootfstype"));
+        assert_eq!(Some("/dev/vdb".to_owned()), kernel_parameter(command_line, "docker-on-kvm.overlay"));
+        assert_eq!(None, kernel_parameter(command_line, "quiet"));
+        assert_eq!(None, kernel_parameter(command_line, "init"));
+    }
+
+    #[test]
+    fn test_parse_user() {
+        let passwd = "root:x:0:0:root:/root:/bin/sh\npostgres:x:70:70::/var/lib/postgresql:/bin/sh\n";
+        let groups = "root:x:0:\nwww-data:x:82:\n";
+
+        assert_eq!(Ok(User { uid: 70, gid: 70, home: Some("/var/lib/postgresql".to_owned()) }), parse_user("postgres", passwd, groups));
+        assert_eq!(Ok(User { uid: 0, gid: 82, home: Some("/root".to_owned()) }), parse_user("0:www-data", passwd, groups));
+        assert_eq!(Ok(User { uid: 1000, gid: 1000, home: None }), parse_user("1000", passwd, groups));
+        assert_eq!(Ok(User { uid: 1000, gid: 5, home: None }), parse_user("1000:5", passwd, groups));
+        assert!(parse_user("nobody", passwd, groups).is_err());
+        assert!(parse_user("postgres:staff", passwd, groups).is_err());
+    }
+}
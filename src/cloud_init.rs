@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use crate::helpers::{self, run_command, CommandError};
+
+/// A cloud-init NoCloud data source, given to the VM as an ISO with the volume label cidata.
+#[derive(Debug, Clone)]
+pub struct CloudInitSeed {
+    pub instance_id: String,
+    pub hostname: String,
+    pub user_data: Option<String>,
+    pub ssh_keys: Vec<String>
+}
+
+impl CloudInitSeed {
+    pub fn meta_data(&self) -> String {
+        let mut meta_data = String::new();
+        meta_data += &format!("instance-id: {}\n", self.instance_id);
+        meta_data += &format!("local-hostname: {}\n", self.hostname);
+
+        if !self.ssh_keys.is_empty() {
+            meta_data += "public-keys:\n";
+            for ssh_key in &self.ssh_keys {
+                meta_data += &format!("  - {}\n", serde_json::to_string(ssh_key.trim()).unwrap());
+            }
+        }
+
+        meta_data
+    }
+
+    /// Writes the seed ISO using genisoimage.
+    pub fn create_iso(&self, iso_file: &Path) -> Result<(), CommandError> {
+        let seed_dir = helpers::temp_filename("-cidata");
+        let result = (|| {
+            let io_error = |err: std::io::Error| CommandError {
+                command: format!("write {}", seed_dir.display()),
+                stderr: err.to_string(),
+                exit_code: None
+            };
+
+            std::fs::create_dir(&seed_dir).map_err(io_error)?;
+            std::fs::write(seed_dir.join("meta-data"), self.meta_data()).map_err(io_error)?;
+
+            // cloud-init requires user-data to exist, even if it just configures the SSH keys from the meta-data
+            let user_data = self.user_data.clone().unwrap_or_else(|| "#cloud-config\n".to_owned());
+            std::fs::write(seed_dir.join("user-data"), user_data).map_err(io_error)?;
+
+            run_command(
+                "genisoimage",
+                [
+                    "-output", iso_file.to_str().unwrap(),
+                    "-volid", "cidata",
+                    "-joliet", "-rock",
+                    seed_dir.join("user-data").to_str().unwrap(),
+                    seed_dir.join("meta-data").to_str().unwrap()
+                ]
+            )?;
+            Ok(())
+        })();
+
+        let _ = std::fs::remove_dir_all(&seed_dir);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta_data() {
+        let seed = CloudInitSeed {
+            instance_id: "a3c1b7e2f4d94c0e8b5a6d7e8f901234".to_owned(),
+            hostname: "web".to_owned(),
+            user_data: None,
+            ssh_keys: vec!["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG user@host\n".to_owned()]
+        };
+
+        assert_eq!(
+            "instance-id: a3c1b7e2f4d94c0e8b5a6d7e8f901234\nlocal-hostname: web\npublic-keys:\n  - \"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG user@host\"\n",
+            seed.meta_data()
+        );
+    }
+}
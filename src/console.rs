@@ -0,0 +1,214 @@
+use std::io::Write;
+use std::time::Duration;
+
+use virt::connect::Connect;
+use virt::domain::Domain;
+
+use crate::error::Error;
+use crate::hypervisor::LifecycleError;
+
+// The stream wrapper of the virt crate only handles NUL terminated strings and frees the stream when it is passed
+// to Domain::open_console, so the stream functions are used directly. The libvirt objects are opaque pointers.
+type ConnectPtr = *mut libc::c_void;
+type DomainPtr = *mut libc::c_void;
+type StreamPtr = *mut libc::c_void;
+
+#[link(name = "virt")]
+extern "C" {
+    fn virStreamNew(conn: ConnectPtr, flags: libc::c_uint) -> StreamPtr;
+    fn virStreamSend(stream: StreamPtr, data: *const libc::c_char, nbytes: libc::size_t) -> libc::c_int;
+    fn virStreamRecv(stream: StreamPtr, data: *mut libc::c_char, nbytes: libc::size_t) -> libc::c_int;
+    fn virStreamFinish(stream: StreamPtr) -> libc::c_int;
+    fn virStreamFree(stream: StreamPtr) -> libc::c_int;
+    fn virDomainOpenConsole(domain: DomainPtr,
+                            dev_name: *const libc::c_char,
+                            stream: StreamPtr,
+                            flags: libc::c_uint) -> libc::c_int;
+}
+
+const VIR_STREAM_NONBLOCK: libc::c_uint = 1;
+const STREAM_WOULD_BLOCK: libc::c_int = -2;
+
+/// Detaches from the console, like in docker.
+const DETACH_KEYS: [u8; 2] = [0x10, 0x11];
+const DETACH_KEYS_DESCRIPTION: &str = "Ctrl-P Ctrl-Q";
+
+struct ConsoleStream {
+    stream: StreamPtr
+}
+
+impl ConsoleStream {
+    fn open(conn: &Connect, domain: &Domain) -> Result<ConsoleStream, virt::error::Error> {
+        let stream = unsafe { virStreamNew(conn.as_ptr() as ConnectPtr, VIR_STREAM_NONBLOCK) };
+        if stream.is_null() {
+            return Err(virt::error::Error::new());
+        }
+
+        let console_stream = ConsoleStream { stream };
+        if unsafe { virDomainOpenConsole(domain.as_ptr() as DomainPtr, std::ptr::null(), stream, 0) } == -1 {
+            return Err(virt::error::Error::new());
+        }
+
+        Ok(console_stream)
+    }
+
+    /// Receives available data, returning None if there is nothing to read and an empty buffer at the end of the stream.
+    fn recv(&self, buffer: &mut [u8]) -> Result<Option<usize>, virt::error::Error> {
+        match unsafe { virStreamRecv(self.stream, buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } {
+            STREAM_WOULD_BLOCK => Ok(None),
+            -1 => Err(virt::error::Error::new()),
+            count => Ok(Some(count as usize))
+        }
+    }
+
+    fn send_all(&self, mut data: &[u8]) -> Result<(), virt::error::Error> {
+        while !data.is_empty() {
+            match unsafe { virStreamSend(self.stream, data.as_ptr() as *const libc::c_char, data.len()) } {
+                STREAM_WOULD_BLOCK => std::thread::sleep(Duration::from_millis(10)),
+                -1 => return Err(virt::error::Error::new()),
+                count => data = &data[count as usize..]
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ConsoleStream {
+    fn drop(&mut self) {
+        unsafe {
+            virStreamFinish(self.stream);
+            virStreamFree(self.stream);
+        }
+    }
+}
+
+/// Puts the terminal in raw mode, restoring the original mode when dropped.
+struct RawTerminal {
+    original: Option<libc::termios>
+}
+
+impl RawTerminal {
+    fn enable() -> std::io::Result<RawTerminal> {
+        // Leave the mode alone when the input is not a terminal, e.g. when piping
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+            return Ok(RawTerminal { original: None });
+        }
+
+        unsafe {
+            let mut original = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            libc::cfmakeraw(&mut raw);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(RawTerminal { original: Some(original) })
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+}
+
+pub enum AttachResult {
+    Detached,
+    Disconnected
+}
+
+/// Attaches the terminal to the serial console of the VM until the detach keys are pressed or the console is closed.
+pub fn attach(conn: &Connect, name: &str) -> Result<AttachResult, Error> {
+    let domain = Domain::lookup_by_name(conn, name).map_err(|_| LifecycleError::NotFound(name.to_owned()))?;
+    let (state, _) = domain.get_state()?;
+    if state != virt::domain::VIR_DOMAIN_RUNNING {
+        return Err(LifecycleError::InvalidState { name: name.to_owned(), state: crate::hypervisor::state_name(state) }.into());
+    }
+
+    let stream = ConsoleStream::open(conn, &domain)?;
+    println!("Attached to the console of VM {}, press {} to detach.", name, DETACH_KEYS_DESCRIPTION);
+
+    let _raw_terminal = RawTerminal::enable()?;
+    proxy(&stream)
+}
+
+fn proxy(stream: &ConsoleStream) -> Result<AttachResult, Error> {
+    let mut stdout = std::io::stdout();
+    let mut buffer = [0u8; 4096];
+    let mut detach_keys_matched = 0;
+    let mut stdin_open = true;
+
+    loop {
+        if stdin_open && stdin_readable(Duration::from_millis(20))? {
+            let count = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+            if count < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+
+            if count == 0 {
+                stdin_open = false;
+            }
+
+            let mut input = Vec::new();
+            for &byte in &buffer[..count as usize] {
+                if byte == DETACH_KEYS[detach_keys_matched] {
+                    detach_keys_matched += 1;
+                    if detach_keys_matched == DETACH_KEYS.len() {
+                        stream.send_all(&input)?;
+                        return Ok(AttachResult::Detached);
+                    }
+                } else {
+                    // Not the detach sequence after all, so pass on the held back keys
+                    input.extend_from_slice(&DETACH_KEYS[..detach_keys_matched]);
+                    if byte == DETACH_KEYS[0] {
+                        detach_keys_matched = 1;
+                    } else {
+                        detach_keys_matched = 0;
+                        input.push(byte);
+                    }
+                }
+            }
+
+            stream.send_all(&input)?;
+        } else if !stdin_open {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        loop {
+            match stream.recv(&mut buffer)? {
+                Some(0) => return Ok(AttachResult::Disconnected),
+                Some(count) => {
+                    stdout.write_all(&buffer[..count])?;
+                    stdout.flush()?;
+                }
+                None => break
+            }
+        }
+    }
+}
+
+fn stdin_readable(timeout: Duration) -> std::io::Result<bool> {
+    let mut poll_fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as libc::c_int) } {
+        -1 => {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true)
+    }
+}
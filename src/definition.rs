@@ -1,239 +1,517 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
 
-use crate::disk_creator::{ DiskInfo};
+use serde::{Serialize, Deserialize};
 
+use crate::disk_creator::{DiskInfo, DiskCreateError};
+use crate::domain_xml::{
+    DomainDefinition, OsSection, Feature, Clock, Timer, Device, DiskDevice, BackingStore,
+    Controller, Interface, InterfaceSource, Channel, Input, Graphics, Filesystem, FilesystemDriver, WriteXml
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
 pub enum FileSystem {
-    Ext4
+    #[default]
+    Ext4,
+    Xfs,
+    Btrfs,
+    /// Read-only, used with a writable overlay layer.
+    Squashfs,
+    /// Read-only, used with a writable overlay layer.
+    Erofs
+}
+
+impl FileSystem {
+    /// Read-only file systems are created from a directory and can't be written to.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, FileSystem::Squashfs | FileSystem::Erofs)
+    }
+}
+
+impl std::str::FromStr for FileSystem {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "ext4" => Ok(FileSystem::Ext4),
+            "xfs" => Ok(FileSystem::Xfs),
+            "btrfs" => Ok(FileSystem::Btrfs),
+            "squashfs" => Ok(FileSystem::Squashfs),
+            "erofs" => Ok(FileSystem::Erofs),
+            _ => Err(format!("Unknown file system '{}', expected ext4, xfs, btrfs, squashfs or erofs", text))
+        }
+    }
 }
 
 impl std::fmt::Display for FileSystem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            FileSystem::Ext4 => write!(f, "ext4")
+            FileSystem::Ext4 => write!(f, "ext4"),
+            FileSystem::Xfs => write!(f, "xfs"),
+            FileSystem::Btrfs => write!(f, "btrfs"),
+            FileSystem::Squashfs => write!(f, "squashfs"),
+            FileSystem::Erofs => write!(f, "erofs")
+        }
+    }
+}
+
+pub enum DomainType {
+    Kvm,
+    Test
+}
+
+impl DomainType {
+    pub fn for_uri(uri: &str) -> DomainType {
+        if uri.starts_with("test:") {
+            DomainType::Test
+        } else {
+            DomainType::Kvm
+        }
+    }
+}
+
+impl std::fmt::Display for DomainType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DomainType::Kvm => write!(f, "kvm"),
+            DomainType::Test => write!(f, "test")
+        }
+    }
+}
+
+/// A MAC address in the locally administered range used by QEMU (52:54:00:xx:xx:xx).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from="String", into="String")]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    /// Derives a MAC address from the last 24 bits of the VM UUID plus the offset, which is the index of the network
+    /// interface or larger when the address derived for a smaller offset is already in use.
+    pub fn from_uuid(uuid: &uuid::Uuid, offset: u32) -> MacAddress {
+        let bytes = uuid.as_bytes();
+        let value = u32::from_be_bytes([0, bytes[13], bytes[14], bytes[15]]).wrapping_add(offset);
+        let [_, byte3, byte4, byte5] = value.to_be_bytes();
+        MacAddress([0x52, 0x54, 0x00, byte3, byte4, byte5])
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac_address: MacAddress) -> Self {
+        mac_address.to_string()
+    }
+}
+
+impl std::str::FromStr for MacAddress {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let parts = text.split(':').collect::<Vec<_>>();
+        if parts.len() != 6 {
+            return Err(format!("Invalid MAC address '{}', expected the format xx:xx:xx:xx:xx:xx", text));
+        }
+
+        let mut bytes = [0; 6];
+        for (byte, part) in bytes.iter_mut().zip(parts) {
+            *byte = u8::from_str_radix(part, 16).map_err(|_| format!("Invalid MAC address '{}'", text))?;
+        }
+
+        if bytes[0] & 0x01 != 0 {
+            return Err(format!("Invalid MAC address '{}', it must not be a multicast address", text));
+        }
+
+        Ok(MacAddress(bytes))
+    }
+}
+
+impl std::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase", tag="type", content="name")]
+pub enum Network {
+    /// A libvirt managed virtual network, such as the NAT network "default".
+    Libvirt(String),
+    /// An existing host bridge.
+    Bridge(String),
+    /// QEMU user-mode (slirp) networking, which works without privileges.
+    User
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Network::Libvirt(name) => write!(f, "network:{}", name),
+            Network::Bridge(name) => write!(f, "bridge:{}", name),
+            Network::User => write!(f, "user")
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub network: Network,
+    pub mac_address: MacAddress
+}
+
+/// How host directories are shared with the VM.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum SharedFolderDriver {
+    /// virtiofs, which requires virtiofsd on the host.
+    VirtioFs,
+    /// 9p, which is slower but served by QEMU itself.
+    NineP
+}
+
+impl SharedFolderDriver {
+    const VIRTIOFSD_PATHS: [&'static str; 3] = ["/usr/libexec/virtiofsd", "/usr/lib/qemu/virtiofsd", "/usr/lib/virtiofsd"];
+
+    /// Uses virtiofs if virtiofsd is installed, and 9p otherwise.
+    pub fn detect() -> SharedFolderDriver {
+        if SharedFolderDriver::VIRTIOFSD_PATHS.iter().any(|path| Path::new(path).exists()) {
+            SharedFolderDriver::VirtioFs
+        } else {
+            SharedFolderDriver::NineP
+        }
+    }
+
+    /// The kernel modules the guest needs to mount the shared folders.
+    pub fn kernel_modules(&self) -> &'static [&'static str] {
+        match self {
+            SharedFolderDriver::VirtioFs => &["virtiofs"],
+            SharedFolderDriver::NineP => &["9pnet_virtio", "9p"]
+        }
+    }
+
+    /// The file system type and mount options used in the guest.
+    pub fn mount_options(&self) -> (&'static str, &'static str) {
+        match self {
+            SharedFolderDriver::VirtioFs => ("virtiofs", "defaults"),
+            SharedFolderDriver::NineP => ("9p", "trans=virtio,version=9p2000.L,msize=262144")
+        }
+    }
+}
+
+impl std::fmt::Display for SharedFolderDriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SharedFolderDriver::VirtioFs => write!(f, "virtiofs"),
+            SharedFolderDriver::NineP => write!(f, "9p")
+        }
+    }
+}
+
+/// A host directory shared with the VM, which mounts it using the tag.
+#[derive(Debug, Clone)]
+pub struct SharedFolder {
+    pub source_dir: String,
+    pub tag: String,
+    pub read_only: bool
+}
+
+/// The kernel command line of the VM. The root disk arguments are filled in when the domain is defined.
+#[derive(Debug, Clone, Default)]
+pub struct KernelCommandLine {
+    /// Replaces the whole generated command line.
+    pub full_override: Option<String>,
+    /// The systemd unit to boot into, defaults to graphical.target (multi-user.target when headless).
+    pub target: Option<String>,
+    /// Boots with another init than systemd.
+    pub init: Option<String>,
+    /// The device with the writable layer that the init mounts over a read-only root file system.
+    pub overlay_device: Option<String>,
+    /// The init that the init given by init hands over to, such as systemd after setting up the overlay.
+    pub next_init: Option<String>,
+    /// Arguments appended to the generated command line.
+    pub extra_args: Vec<String>,
+    /// Adds the serial port as a kernel console.
+    pub serial_console: bool
+}
+
+impl KernelCommandLine {
+    pub fn build(&self, root_device: &str, root_filesystem: &FileSystem, headless: bool) -> String {
+        if let Some(full_override) = &self.full_override {
+            return full_override.clone();
+        }
+
+        let default_target = if headless { "multi-user.target" } else { "graphical.target" };
+        let mut args = vec![
+            format!("root=/dev/{}", root_device),
+            if root_filesystem.is_read_only() { "ro" } else { "rw" }.to_owned(),
+            format!("rootfstype={}", root_filesystem)
+        ];
+
+        if let Some(init) = &self.init {
+            args.push(format!("init={}", init));
+        }
+
+        if let Some(overlay_device) = &self.overlay_device {
+            args.push(format!("docker-on-kvm.overlay=/dev/{}", overlay_device));
+        }
+
+        if let Some(next_init) = &self.next_init {
+            args.push(format!("docker-on-kvm.init={}", next_init));
+        }
+
+        if self.init.is_none() || self.next_init.is_some() {
+            args.push(format!("systemd.unit={}", self.target.as_deref().unwrap_or(default_target)));
+        }
+
+        // The last console given becomes /dev/console
+        if headless {
+            args.push("console=tty0".to_owned());
+            args.push("console=ttyS0".to_owned());
+        } else if self.serial_console {
+            args.push("console=ttyS0".to_owned());
+            args.push("console=tty0".to_owned());
+        }
+
+        args.extend(self.extra_args.iter().cloned());
+        args.join(" ")
+    }
+}
+
 pub enum Disk {
     File { filename: String, filesystem: FileSystem }
 }
 
 pub struct VirtualMachine {
+    pub domain_type: DomainType,
     pub name: String,
     pub uuid: Option<String>,
     pub kernel_file: String,
     pub initrd_file: String,
     pub root_disk: Disk,
+    /// Extra disks, such as volumes, attached as vdb, vdc and so on.
+    pub data_disks: Vec<Disk>,
+    /// A cloud-init seed ISO attached as a CD-ROM.
+    pub seed_disk: Option<String>,
+    pub networks: Vec<NetworkInterface>,
+    pub shared_folders: Vec<SharedFolder>,
+    pub shared_folder_driver: SharedFolderDriver,
+    pub headless: bool,
+    pub kernel_command_line: KernelCommandLine,
     pub ram_in_bytes: u64,
-    pub num_cpus: u64
+    pub num_cpus: u64,
+    pub machine: String,
+    pub uefi_loader: Option<String>,
+    pub nvram_directory: PathBuf
 }
 
 impl VirtualMachine {
-    pub fn get_xml(&self) -> Option<String> {
-        let (root_filesystem_type, root_disk_device_id, root_disk_xml) = match &self.root_disk {
-            Disk::File { filename, filesystem } => {
-                let disk_info = DiskInfo::for_disk_file(Path::new(filename))?;
-                let backing_file = match disk_info.backing_file {
-                    Some(backing_file) => {
-                        let backing_file_info = DiskInfo::for_disk_file(Path::new(&backing_file))?;
-
-                        format!(
-                            r#"
-                            <backingStore type="file">
-                                <format type="{}"/>
-                                <source file="{}"/>
-                            </backingStore>"#,
-                            backing_file_info.format,
-                            backing_file
-                        )
-                    }
-                    None => String::new()
-                };
-
-                let device_id = "vda";
-                let xml = format!(
-                    r#"
-                    <disk type="file" device="disk">
-                      <driver name="qemu" type="{format}"/>
-                      <source file="{filename}"/>
-                      <target dev="{device_id}" bus="virtio"/>
-                      <address type="pci" domain="0x0000" bus="0x03" slot="0x00" function="0x0"/>
-                      {backing_file}
-                    </disk>"#,
-                    filename = filename,
-                    format = disk_info.format.to_string(),
-                    device_id = device_id,
-                    backing_file = backing_file
-                );
-
-                (filesystem, device_id, xml)
-            }
+    pub fn nvram_file(&self) -> PathBuf {
+        self.nvram_directory.join(format!("{}_VARS.fd", self.name))
+    }
+
+    pub fn get_definition(&self) -> Result<DomainDefinition, DiskCreateError> {
+        let root_disk = disk_device(&self.root_disk, ROOT_DISK_DEVICE_ID)?;
+        let root_filesystem_type = match &self.root_disk {
+            Disk::File { filesystem, .. } => filesystem
         };
 
-        let uuid = match self.uuid.as_ref() {
-            Some(uuid) => format!("<uuid>{}</uuid>", uuid),
-            None => String::new()
+        let mut definition = DomainDefinition {
+            domain_type: self.domain_type.to_string(),
+            name: self.name.clone(),
+            uuid: self.uuid.clone(),
+            memory_in_bytes: self.ram_in_bytes,
+            num_cpus: self.num_cpus,
+            shared_memory: !self.shared_folders.is_empty() && self.shared_folder_driver == SharedFolderDriver::VirtioFs,
+            os: OsSection {
+                arch: "x86_64".to_owned(),
+                machine: Some(self.machine.clone()),
+                loader: self.uefi_loader.clone(),
+                nvram: self.uefi_loader.as_ref().map(|_| self.nvram_file().to_str().unwrap().to_owned()),
+                kernel: Some(self.kernel_file.clone()),
+                initrd: Some(self.initrd_file.clone()),
+                cmdline: Some(self.kernel_command_line.build(ROOT_DISK_DEVICE_ID, root_filesystem_type, self.headless)),
+                boot_device: "hd".to_owned()
+            },
+            features: vec![Feature::Acpi, Feature::Apic, Feature::VmPortOff],
+            cpu_mode: "host-model".to_owned(),
+            clock: Clock {
+                offset: "utc".to_owned(),
+                timers: vec![
+                    Timer { name: "rtc".to_owned(), tick_policy: Some("catchup".to_owned()), present: None },
+                    Timer { name: "pit".to_owned(), tick_policy: Some("delay".to_owned()), present: None },
+                    Timer { name: "hpet".to_owned(), tick_policy: None, present: Some(false) }
+                ]
+            },
+            on_poweroff: "destroy".to_owned(),
+            on_reboot: "restart".to_owned(),
+            on_crash: "destroy".to_owned(),
+            devices: Vec::new()
         };
 
-        Some(format!(
-            r#"
-            <domain type="kvm">
-              <name>{name}</name>
-              {uuid}
-              <metadata>
-                <libosinfo:libosinfo xmlns:libosinfo="http://libosinfo.org/xmlns/libvirt/domain/1.0">
-                  <libosinfo:os id="http://ubuntu.com/ubuntu/18.04"/>
-                </libosinfo:libosinfo>
-              </metadata>
-              <memory unit="B">{ram_in_bytes}</memory>
-              <currentMemory unit="B">{ram_in_bytes}</currentMemory>
-              <vcpu placement="static">{num_cpus}</vcpu>
-              <os>
-                <type arch="x86_64" machine="pc-q35-4.2">hvm</type>
-                <loader readonly="yes" type="pflash">/usr/share/OVMF/OVMF_CODE.ms.fd</loader>
-                <nvram>/var/lib/libvirt/qemu/nvram/{name}_VARS.fd</nvram>
-                <kernel>{kernel_file}</kernel>
-                <initrd>{initrd_file}</initrd>
-                <cmdline>root=/dev/{root_disk_device_id} rw rootfstype={root_disk_type} systemd.unit=graphical.target</cmdline>
-                <boot dev="hd"/>
-              </os>
-              <features>
-                <acpi/>
-                <apic/>
-                <vmport state="off"/>
-              </features>
-              <cpu mode="host-model" check="partial"/>
-              <clock offset="utc">
-                <timer name="rtc" tickpolicy="catchup"/>
-                <timer name="pit" tickpolicy="delay"/>
-                <timer name="hpet" present="no"/>
-              </clock>
-              <on_poweroff>destroy</on_poweroff>
-              <on_reboot>restart</on_reboot>
-              <on_crash>destroy</on_crash>
-              <pm>
-                <suspend-to-mem enabled="no"/>
-                <suspend-to-disk enabled="no"/>
-              </pm>
-              <devices>
-                <emulator>/usr/bin/qemu-system-x86_64</emulator>
-                {root_disk_xml}
-                <controller type="usb" index="0" model="ich9-ehci1">
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x1d" function="0x7"/>
-                </controller>
-                <controller type="usb" index="0" model="ich9-uhci1">
-                  <master startport="0"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x1d" function="0x0" multifunction="on"/>
-                </controller>
-                <controller type="usb" index="0" model="ich9-uhci2">
-                  <master startport="2"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x1d" function="0x1"/>
-                </controller>
-                <controller type="usb" index="0" model="ich9-uhci3">
-                  <master startport="4"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x1d" function="0x2"/>
-                </controller>
-                <controller type="sata" index="0">
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x1f" function="0x2"/>
-                </controller>
-                <controller type="pci" index="0" model="pcie-root"/>
-                <controller type="pci" index="1" model="pcie-root-port">
-                  <model name="pcie-root-port"/>
-                  <target chassis="1" port="0x10"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x02" function="0x0" multifunction="on"/>
-                </controller>
-                <controller type="pci" index="2" model="pcie-root-port">
-                  <model name="pcie-root-port"/>
-                  <target chassis="2" port="0x11"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x02" function="0x1"/>
-                </controller>
-                <controller type="pci" index="3" model="pcie-root-port">
-                  <model name="pcie-root-port"/>
-                  <target chassis="3" port="0x12"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x02" function="0x2"/>
-                </controller>
-                <controller type="pci" index="4" model="pcie-root-port">
-                  <model name="pcie-root-port"/>
-                  <target chassis="4" port="0x13"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x02" function="0x3"/>
-                </controller>
-                <controller type="pci" index="5" model="pcie-root-port">
-                  <model name="pcie-root-port"/>
-                  <target chassis="5" port="0x14"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x02" function="0x4"/>
-                </controller>
-                <controller type="pci" index="6" model="pcie-root-port">
-                  <model name="pcie-root-port"/>
-                  <target chassis="6" port="0x15"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x02" function="0x5"/>
-                </controller>
-                <controller type="virtio-serial" index="0">
-                  <address type="pci" domain="0x0000" bus="0x02" slot="0x00" function="0x0"/>
-                </controller>
-                <interface type="network">
-                  <mac address="52:54:00:af:aa:a5"/>
-                  <source network="default"/>
-                  <model type="virtio"/>
-                  <address type="pci" domain="0x0000" bus="0x01" slot="0x00" function="0x0"/>
-                </interface>
-                <serial type="pty">
-                  <target type="isa-serial" port="0">
-                    <model name="isa-serial"/>
-                  </target>
-                </serial>
-                <console type="pty">
-                  <target type="serial" port="0"/>
-                </console>
-                <channel type="unix">
-                  <target type="virtio" name="org.qemu.guest_agent.0"/>
-                  <address type="virtio-serial" controller="0" bus="0" port="1"/>
-                </channel>
-                <channel type="spicevmc">
-                  <target type="virtio" name="com.redhat.spice.0"/>
-                  <address type="virtio-serial" controller="0" bus="0" port="2"/>
-                </channel>
-                <input type="tablet" bus="usb">
-                  <address type="usb" bus="0" port="1"/>
-                </input>
-                <input type="mouse" bus="ps2"/>
-                <input type="keyboard" bus="ps2"/>
-                <graphics type="spice" autoport="yes">
-                  <listen type="address"/>
-                  <image compression="off"/>
-                </graphics>
-                <sound model="ich9">
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x1b" function="0x0"/>
-                </sound>
-                <video>
-                  <model type="virtio" heads="1" primary="yes"/>
-                  <alias name="video0"/>
-                  <address type="pci" domain="0x0000" bus="0x00" slot="0x01" function="0x0"/>
-                </video>
-                <redirdev bus="usb" type="spicevmc">
-                  <address type="usb" bus="0" port="2"/>
-                </redirdev>
-                <redirdev bus="usb" type="spicevmc">
-                  <address type="usb" bus="0" port="3"/>
-                </redirdev>
-                <memballoon model="virtio">
-                  <address type="pci" domain="0x0000" bus="0x04" slot="0x00" function="0x0"/>
-                </memballoon>
-                <rng model="virtio">
-                  <backend model="random">/dev/urandom</backend>
-                  <address type="pci" domain="0x0000" bus="0x05" slot="0x00" function="0x0"/>
-                </rng>
-              </devices>
-            </domain>
-            "#,
-            name = self.name,
-            uuid = uuid,
-            kernel_file = self.kernel_file,
-            initrd_file = self.initrd_file,
-            root_disk_xml = root_disk_xml,
-            root_disk_type = root_filesystem_type,
-            root_disk_device_id = root_disk_device_id,
-            ram_in_bytes = self.ram_in_bytes,
-            num_cpus = self.num_cpus
-        ))
-    }
-}
\ No newline at end of file
+        definition.add_device(Device::Emulator("/usr/bin/qemu-system-x86_64".to_owned()));
+        definition.add_device(Device::Disk(root_disk));
+
+        for (index, data_disk) in self.data_disks.iter().enumerate() {
+            definition.add_device(Device::Disk(disk_device(data_disk, &data_disk_device_id(index))?));
+        }
+
+        if let Some(seed_disk) = &self.seed_disk {
+            definition.add_device(Device::Disk(DiskDevice {
+                device: "cdrom".to_owned(),
+                format: "raw".to_owned(),
+                source_file: seed_disk.clone(),
+                target_dev: "sda".to_owned(),
+                target_bus: "sata".to_owned(),
+                readonly: true,
+                backing_store: None
+            }));
+        }
+
+        if self.machine == "q35" || self.machine.starts_with("pc-q35") {
+            definition.add_device(Device::Controller(Controller { controller_type: "pci".to_owned(), index: 0, model: Some("pcie-root".to_owned()) }));
+        }
+        definition.add_device(Device::Controller(Controller { controller_type: "usb".to_owned(), index: 0, model: Some("qemu-xhci".to_owned()) }));
+        definition.add_device(Device::Controller(Controller { controller_type: "sata".to_owned(), index: 0, model: None }));
+        definition.add_device(Device::Controller(Controller { controller_type: "virtio-serial".to_owned(), index: 0, model: None }));
+
+        for network_interface in &self.networks {
+            let source = match &network_interface.network {
+                Network::Libvirt(name) => InterfaceSource::Network(name.clone()),
+                Network::Bridge(name) => InterfaceSource::Bridge(name.clone()),
+                Network::User => InterfaceSource::User
+            };
+
+            definition.add_device(Device::Interface(Interface {
+                source,
+                mac: Some(network_interface.mac_address.to_string()),
+                model: "virtio".to_owned()
+            }));
+        }
+
+        for shared_folder in &self.shared_folders {
+            definition.add_device(Device::Filesystem(Filesystem {
+                driver: match self.shared_folder_driver {
+                    SharedFolderDriver::VirtioFs => FilesystemDriver::VirtioFs,
+                    SharedFolderDriver::NineP => FilesystemDriver::Path
+                },
+                source_dir: shared_folder.source_dir.clone(),
+                target_tag: shared_folder.tag.clone(),
+                readonly: shared_folder.read_only
+            }));
+        }
+
+        definition.add_device(Device::Serial { port: 0 });
+        definition.add_device(Device::Console { port: 0 });
+        definition.add_device(Device::Channel(Channel { channel_type: "unix".to_owned(), target_name: "org.qemu.guest_agent.0".to_owned() }));
+        definition.add_device(Device::Channel(Channel { channel_type: "spicevmc".to_owned(), target_name: "com.redhat.spice.0".to_owned() }));
+        definition.add_device(Device::Input(Input { input_type: "tablet".to_owned(), bus: "usb".to_owned() }));
+        definition.add_device(Device::Input(Input { input_type: "mouse".to_owned(), bus: "ps2".to_owned() }));
+        definition.add_device(Device::Input(Input { input_type: "keyboard".to_owned(), bus: "ps2".to_owned() }));
+        definition.add_device(Device::Graphics(Graphics::Spice));
+        definition.add_device(Device::Sound { model: "ich9".to_owned() });
+        definition.add_device(Device::Video { model: "virtio".to_owned() });
+        definition.add_device(Device::RedirDev { bus: "usb".to_owned(), redirect_type: "spicevmc".to_owned() });
+        definition.add_device(Device::RedirDev { bus: "usb".to_owned(), redirect_type: "spicevmc".to_owned() });
+        definition.add_device(Device::MemBalloon { model: "virtio".to_owned() });
+        definition.add_device(Device::Rng { model: "virtio".to_owned(), backend: "/dev/urandom".to_owned() });
+
+        if self.headless {
+            definition.remove_devices(|device| {
+                match device {
+                    Device::Graphics(_) | Device::Sound { .. } | Device::Video { .. } | Device::RedirDev { .. } => true,
+                    Device::Channel(channel) => channel.channel_type == "spicevmc",
+                    Device::Input(input) => input.input_type == "tablet",
+                    _ => false
+                }
+            });
+        }
+
+        Ok(definition)
+    }
+
+    pub fn get_xml(&self) -> Result<String, DiskCreateError> {
+        Ok(self.get_definition()?.to_xml())
+    }
+}
+
+pub const ROOT_DISK_DEVICE_ID: &str = "vda";
+
+/// The device name of the data disk with the given index, following the root disk.
+pub fn data_disk_device_id(index: usize) -> String {
+    format!("vd{}", (b'b' + index as u8) as char)
+}
+
+fn disk_device(disk: &Disk, device_id: &str) -> Result<DiskDevice, DiskCreateError> {
+    match disk {
+        Disk::File { filename, filesystem } => {
+            let disk_info = DiskInfo::for_disk_file(Path::new(filename))?;
+            let backing_store = match disk_info.backing_file {
+                Some(backing_file) => {
+                    let backing_file_info = DiskInfo::for_disk_file(Path::new(&backing_file))?;
+                    Some(BackingStore {
+                        format: backing_file_info.format.to_string(),
+                        source_file: backing_file
+                    })
+                }
+                None => None
+            };
+
+            Ok(DiskDevice {
+                device: "disk".to_owned(),
+                format: disk_info.format.to_string(),
+                source_file: filename.clone(),
+                target_dev: device_id.to_owned(),
+                target_bus: "virtio".to_owned(),
+                readonly: filesystem.is_read_only(),
+                backing_store
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mac_address_from_uuid() {
+        let uuid = uuid::Uuid::parse_str("a1b2c3d4-0000-0000-0000-00000012fffe").unwrap();
+        assert_eq!("52:54:00:12:ff:fe", MacAddress::from_uuid(&uuid, 0).to_string());
+        assert_eq!("52:54:00:13:00:00", MacAddress::from_uuid(&uuid, 2).to_string());
+
+        let uuid = uuid::Uuid::parse_str("a1b2c3d4-0000-0000-0000-000000ffffff").unwrap();
+        assert_eq!("52:54:00:00:00:00", MacAddress::from_uuid(&uuid, 1).to_string());
+    }
+
+    #[test]
+    fn test_kernel_command_line() {
+        let mut kernel_command_line = KernelCommandLine {
+            full_override: None,
+            target: None,
+            init: None,
+            overlay_device: None,
+            next_init: None,
+            extra_args: Vec::new(),
+            serial_console: false
+        };
+        assert_eq!(
+            "root=/dev/vda rw rootfstype=xfs systemd.unit=multi-user.target console=tty0 console=ttyS0",
+            kernel_command_line.build("vda", &FileSystem::Xfs, true)
+        );
+
+        kernel_command_line.init = Some("/sbin/docker-on-kvm-init".to_owned());
+        kernel_command_line.overlay_device = Some("vdb".to_owned());
+        kernel_command_line.next_init = Some("/sbin/init".to_owned());
+        assert_eq!(
+            "root=/dev/vda ro rootfstype=squashfs init=/sbin/docker-on-kvm-init docker-on-kvm.overlay=/dev/vdb docker-on-kvm.init=/sbin/init systemd.unit=graphical.target",
+            kernel_command_line.build("vda", &FileSystem::Squashfs, false)
+        );
+    }
+}
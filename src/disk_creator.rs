@@ -1,16 +1,32 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use regex::Regex;
+use serde::{Serialize, Deserialize};
 
 use crate::definition::FileSystem;
-use crate::helpers::run_command;
+use crate::helpers::{run_command, CommandError};
+use crate::helpers;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
 pub enum DiskFormat {
     Raw,
     Qcow2
 }
 
+impl std::str::FromStr for DiskFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "raw" => Ok(DiskFormat::Raw),
+            "qcow2" => Ok(DiskFormat::Qcow2),
+            _ => Err(format!("Unknown disk format '{}', expected raw or qcow2", text))
+        }
+    }
+}
+
 impl std::fmt::Display for DiskFormat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -22,10 +38,139 @@ impl std::fmt::Display for DiskFormat {
 
 #[derive(Debug)]
 pub enum DiskCreateError {
+    IO(std::io::Error),
     DiskAlreadyExists,
     DirectoryNotExist,
     BackingFileNotExist,
-    FailedCreate
+    FailedCreate(CommandError),
+    FailedCopy(CommandError),
+    FailedInfo(CommandError),
+    UnknownFormat(String),
+    DiskTooSmall { requested_bytes: u64, required_bytes: u64 },
+    CannotShrink { size_in_bytes: u64, requested_bytes: u64 }
+}
+
+impl std::fmt::Display for DiskCreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskCreateError::IO(err) => write!(f, "I/O error: {}", err),
+            DiskCreateError::DiskAlreadyExists => write!(f, "The disk already exists"),
+            DiskCreateError::DirectoryNotExist => write!(f, "The source directory does not exist"),
+            DiskCreateError::BackingFileNotExist => write!(f, "The backing file does not exist"),
+            DiskCreateError::FailedCreate(err) => write!(f, "Failed to create disk: {}", err),
+            DiskCreateError::FailedCopy(err) => write!(f, "Failed to copy files to disk (is --disk-size large enough?): {}", err),
+            DiskCreateError::FailedInfo(err) => write!(f, "Failed to get disk information: {}", err),
+            DiskCreateError::UnknownFormat(disk) => write!(f, "Unknown format of disk {}", disk),
+            DiskCreateError::DiskTooSmall { requested_bytes, required_bytes } => {
+                write!(
+                    f,
+                    "The disk size of {} MB is too small for the image, which needs at least {} MB",
+                    requested_bytes / MEGABYTE,
+                    required_bytes.div_ceil(MEGABYTE)
+                )
+            }
+            DiskCreateError::CannotShrink { size_in_bytes, requested_bytes } => {
+                write!(f, "The disk is {} MB and can't be shrunk to {} MB", size_in_bytes / MEGABYTE, requested_bytes / MEGABYTE)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for DiskCreateError {
+    fn from(err: std::io::Error) -> Self {
+        DiskCreateError::IO(err)
+    }
+}
+
+impl From<CommandError> for DiskCreateError {
+    fn from(err: CommandError) -> Self {
+        DiskCreateError::FailedCreate(err)
+    }
+}
+
+const MEGABYTE: u64 = 1024 * 1024;
+
+/// The space used by a directory tree, as counted by du.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirectoryUsage {
+    pub size_in_bytes: u64,
+    pub inodes: u64
+}
+
+impl DirectoryUsage {
+    pub fn for_directory(directory: &Path) -> Result<DirectoryUsage, DiskCreateError> {
+        if !directory.exists() {
+            return Err(DiskCreateError::DirectoryNotExist);
+        }
+
+        // The extracted images contain files only readable by root
+        let directory_str = directory.to_str().unwrap();
+        let size_output = run_command("sudo", ["-S", "du", "-sx", "--block-size=1", directory_str]).map_err(DiskCreateError::FailedInfo)?;
+        let inodes_output = run_command("sudo", ["-S", "du", "-sx", "--inodes", directory_str]).map_err(DiskCreateError::FailedInfo)?;
+
+        let parse_total = |output: &str| {
+            output
+                .split_whitespace()
+                .next()
+                .and_then(|total| total.parse::<u64>().ok())
+                .ok_or_else(|| DiskCreateError::FailedInfo(CommandError {
+                    command: format!("du {}", directory_str),
+                    stderr: format!("Unexpected output: {}", output.trim()),
+                    exit_code: None
+                }))
+        };
+
+        Ok(DirectoryUsage {
+            size_in_bytes: parse_total(&size_output)?,
+            inodes: parse_total(&inodes_output)?
+        })
+    }
+
+    /// The smallest disk with the given file system that fits the directory while leaving the headroom (in percent of
+    /// the space needed) free, rounded up to whole megabytes.
+    pub fn disk_size(&self, file_system: FileSystem, headroom_percent: u64) -> u64 {
+        // Ext4 creates a fixed number of inodes, one per 16 KiB by default, while the others allocate them as needed
+        let bytes_per_inode = match file_system {
+            FileSystem::Ext4 => 16 * 1024,
+            _ => 0
+        };
+
+        // Room for the journal, metadata and the blocks reserved for root
+        let overhead_bytes = 128 * MEGABYTE;
+        let reserved_percent = if file_system == FileSystem::Ext4 { 5 } else { 0 };
+
+        let needed_bytes = self.size_in_bytes.max(self.inodes * bytes_per_inode);
+        let with_headroom = needed_bytes * (100 + headroom_percent) / 100;
+        let disk_size = with_headroom * 100 / (100 - reserved_percent) + overhead_bytes;
+        disk_size.div_ceil(MEGABYTE) * MEGABYTE
+    }
+
+    /// The requested size if the content fits in it, and otherwise the size that fits the content with the headroom.
+    /// The headroom only applies to the computed size, so a requested size just needs to fit the content.
+    pub fn choose_disk_size(&self,
+                            requested_size_in_bytes: Option<u64>,
+                            file_system: FileSystem,
+                            headroom_percent: u64) -> Result<u64, DiskCreateError> {
+        match requested_size_in_bytes {
+            Some(requested_bytes) => {
+                let required_bytes = self.disk_size(file_system, 0);
+                if requested_bytes < required_bytes {
+                    return Err(DiskCreateError::DiskTooSmall { requested_bytes, required_bytes });
+                }
+
+                Ok(requested_bytes)
+            }
+            None => Ok(self.disk_size(file_system, headroom_percent))
+        }
+    }
+}
+
+/// The size to create the disk for the directory with, see DirectoryUsage::choose_disk_size.
+pub fn disk_size_for_directory(directory: &Path,
+                               requested_size_in_bytes: Option<u64>,
+                               file_system: FileSystem,
+                               headroom_percent: u64) -> Result<u64, DiskCreateError> {
+    DirectoryUsage::for_directory(directory)?.choose_disk_size(requested_size_in_bytes, file_system, headroom_percent)
 }
 
 pub fn create_from_directory(disk_file: &Path,
@@ -42,40 +187,333 @@ pub fn create_from_directory(disk_file: &Path,
 
     let tmp_mount_path = Path::new("/tmp/docker-on-kvm-mount");
     if !tmp_mount_path.exists() {
-        std::fs::create_dir(tmp_mount_path).unwrap();
+        std::fs::create_dir(tmp_mount_path).map_err(DiskCreateError::IO)?;
     }
 
-    let tmp_mount_path_str = tmp_mount_path.to_str().unwrap();
     let disk_file_str = disk_file.to_str().unwrap();
 
-    run_command("fallocate", &["-l", &disk_size_in_bytes.to_string(), disk_file_str])
-        .map_err(|_| DiskCreateError::FailedCreate)?;
+    // Read-only file systems are built from the directory, and so are as large as needed
+    if file_system.is_read_only() {
+        let result = create_read_only(disk_file_str, file_system, directory).map_err(DiskCreateError::FailedCreate);
+        if result.is_err() && disk_file.exists() {
+            std::fs::remove_file(disk_file).map_err(DiskCreateError::IO)?;
+        }
 
-    match file_system {
-        FileSystem::Ext4 => {
-            run_command("mkfs.ext4", &["-F", disk_file_str])
-                .map_err(|_| DiskCreateError::FailedCreate)?;
+        return result;
+    }
 
-            run_command("sudo", &["-S", "mount", "-t", "ext4", "-o", "loop", disk_file_str, tmp_mount_path_str])
-                .map_err(|_| DiskCreateError::FailedCreate)?;
-        }
+    run_command("fallocate", ["-l", &disk_size_in_bytes.to_string(), disk_file_str])
+        .map_err(DiskCreateError::FailedCreate)?;
+
+    // Don't leave a half-filled disk behind, as it would be reused by the next run
+    let result = format_and_copy(disk_file_str, file_system, directory, tmp_mount_path);
+    if result.is_err() {
+        std::fs::remove_file(disk_file).map_err(DiskCreateError::IO)?;
     }
 
+    result
+}
+
+fn format_and_copy(disk_file_str: &str,
+                   file_system: FileSystem,
+                   directory: &Path,
+                   tmp_mount_path: &Path) -> Result<(), DiskCreateError> {
+    make_file_system(disk_file_str, &file_system).map_err(DiskCreateError::FailedCreate)?;
+
+    let mounted_disk = MountedDisk::mount(disk_file_str, Some(&file_system), true, tmp_mount_path)
+        .map_err(DiskCreateError::FailedCreate)?;
+
     let result = run_command(
         "sudo",
-        &["-S", "cp", "-ax", directory.join(".").to_str().unwrap(), tmp_mount_path.join(".").to_str().unwrap()]
-    ).map_err(|err| {
-        println!("{}", err);
-        DiskCreateError::DirectoryNotExist
-    });
+        ["-S", "cp", "-ax", directory.join(".").to_str().unwrap(), tmp_mount_path.join(".").to_str().unwrap()]
+    ).map_err(DiskCreateError::FailedCopy);
 
-    run_command("sudo", &["-S", "umount", tmp_mount_path_str])
-        .map_err(|_| DiskCreateError::FailedCreate)?;
+    mounted_disk.unmount().map_err(DiskCreateError::FailedCreate)?;
 
     result?;
     Ok(())
 }
 
+fn create_read_only(disk_file_str: &str, file_system: FileSystem, directory: &Path) -> Result<(), CommandError> {
+    let directory_str = directory.to_str().unwrap();
+    match file_system {
+        FileSystem::Squashfs => {
+            run_command("sudo", ["-S", "mksquashfs", directory_str, disk_file_str, "-noappend", "-quiet"])?;
+        }
+        FileSystem::Erofs => {
+            run_command("sudo", ["-S", "mkfs.erofs", disk_file_str, directory_str])?;
+        }
+        _ => unreachable!("{} is not a read-only file system", file_system)
+    }
+
+    Ok(())
+}
+
+/// Creates the file system on the device or disk image.
+pub fn make_file_system(target: &str, file_system: &FileSystem) -> Result<(), CommandError> {
+    match file_system {
+        FileSystem::Ext4 => {
+            run_command("sudo", ["-S", "mkfs.ext4", "-F", target])?;
+        }
+        FileSystem::Xfs => {
+            run_command("sudo", ["-S", "mkfs.xfs", "-f", target])?;
+        }
+        FileSystem::Btrfs => {
+            run_command("sudo", ["-S", "mkfs.btrfs", "-f", target])?;
+        }
+        FileSystem::Squashfs | FileSystem::Erofs => {
+            return Err(CommandError {
+                command: format!("mkfs {}", target),
+                stderr: format!("{} file systems can only be created from a directory", file_system),
+                exit_code: None
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates an empty disk image with a file system, such as a data volume.
+pub fn create_empty(disk_file: &Path,
+                    disk_size_in_bytes: u64,
+                    format: DiskFormat,
+                    file_system: &FileSystem) -> Result<(), DiskCreateError> {
+    if disk_file.exists() {
+        return Err(DiskCreateError::DiskAlreadyExists);
+    }
+
+    let disk_file_str = disk_file.to_str().unwrap();
+    run_command("qemu-img", ["create", "-f", &format.to_string(), disk_file_str, &disk_size_in_bytes.to_string()])
+        .map_err(DiskCreateError::FailedCreate)?;
+
+    let result = match format {
+        DiskFormat::Raw => make_file_system(disk_file_str, file_system),
+        DiskFormat::Qcow2 => {
+            NbdDevice::connect(disk_file).and_then(|nbd_device| {
+                let result = make_file_system(nbd_device.device(), file_system);
+                nbd_device.disconnect()?;
+                result
+            })
+        }
+    };
+
+    if let Err(err) = result {
+        std::fs::remove_file(disk_file).map_err(DiskCreateError::IO)?;
+        return Err(DiskCreateError::FailedCreate(err));
+    }
+
+    Ok(())
+}
+
+/// A file system mounted with sudo. Must be unmounted explicitly to be able to report errors.
+pub struct MountedDisk {
+    mount_path: PathBuf
+}
+
+impl MountedDisk {
+    /// Mounts the device, or disk image if loop is set. The file system type is detected if not given.
+    pub fn mount(device: &str, file_system: Option<&FileSystem>, loop_device: bool, mount_path: &Path) -> Result<MountedDisk, CommandError> {
+        let mut args = vec!["-S".to_owned(), "mount".to_owned()];
+        if let Some(file_system) = file_system {
+            args.push("-t".to_owned());
+            args.push(file_system.to_string());
+        }
+
+        if loop_device {
+            args.push("-o".to_owned());
+            args.push("loop".to_owned());
+        }
+
+        args.push(device.to_owned());
+        args.push(mount_path.to_str().unwrap().to_owned());
+        run_command("sudo", args)?;
+
+        Ok(MountedDisk { mount_path: mount_path.to_owned() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.mount_path
+    }
+
+    pub fn unmount(self) -> Result<(), CommandError> {
+        run_command("sudo", ["-S", "umount", self.mount_path.to_str().unwrap()])?;
+        Ok(())
+    }
+}
+
+/// A qcow2 image connected to a network block device with qemu-nbd, which makes it possible to mount it.
+pub struct NbdDevice {
+    device: String
+}
+
+impl NbdDevice {
+    pub fn connect(disk_file: &Path) -> Result<NbdDevice, CommandError> {
+        run_command("sudo", ["-S", "modprobe", "nbd"])?;
+
+        // Devices in use have a non-zero size
+        let device = (0..16)
+            .map(|index| format!("nbd{}", index))
+            .find(|device| {
+                std::fs::read_to_string(format!("/sys/block/{}/size", device))
+                    .map(|size| size.trim() == "0")
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| CommandError {
+                command: "qemu-nbd".to_owned(),
+                stderr: "no free network block device".to_owned(),
+                exit_code: None
+            })?;
+
+        let device = format!("/dev/{}", device);
+        run_command("sudo", ["-S", "qemu-nbd", "--connect", &device, disk_file.to_str().unwrap()])?;
+
+        // The device exists before the image has been attached to it
+        let timeout = Duration::from_secs(5);
+        let pid_file = format!("/sys/block/{}/pid", &device[5..]);
+        let start_time = Instant::now();
+        while !Path::new(&pid_file).exists() {
+            if start_time.elapsed() >= timeout {
+                let _ = run_command("sudo", ["-S", "qemu-nbd", "--disconnect", &device]);
+                return Err(CommandError {
+                    command: format!("qemu-nbd --connect {} {}", device, disk_file.display()),
+                    stderr: format!("the device was not ready after {} seconds", timeout.as_secs()),
+                    exit_code: None
+                });
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(NbdDevice { device })
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    pub fn disconnect(self) -> Result<(), CommandError> {
+        run_command("sudo", ["-S", "qemu-nbd", "--disconnect", &self.device])?;
+        Ok(())
+    }
+}
+
+/// Mounts the file system in the disk image with qemu-nbd and runs the function with the mount path, unmounting afterwards.
+pub fn with_mounted_image<T, E, F>(disk_file: &Path, function: F) -> Result<T, E>
+    where
+        E: From<CommandError> + From<std::io::Error>,
+        F: FnOnce(&Path) -> Result<T, E> {
+    let mount_path = helpers::temp_filename("-mount");
+    std::fs::create_dir(&mount_path)?;
+
+    let nbd_device = match NbdDevice::connect(disk_file) {
+        Ok(nbd_device) => nbd_device,
+        Err(err) => {
+            std::fs::remove_dir(&mount_path)?;
+            return Err(err.into());
+        }
+    };
+
+    let mounted_disk = match MountedDisk::mount(nbd_device.device(), None, false, &mount_path) {
+        Ok(mounted_disk) => mounted_disk,
+        Err(err) => {
+            nbd_device.disconnect()?;
+            std::fs::remove_dir(&mount_path)?;
+            return Err(err.into());
+        }
+    };
+
+    let result = function(mounted_disk.path());
+
+    mounted_disk.unmount()?;
+    nbd_device.disconnect()?;
+    std::fs::remove_dir(&mount_path)?;
+
+    result
+}
+
+/// Mounts the root file system of a VM and runs the function with the mount path, unmounting afterwards.
+/// Read-only root file systems are mounted together with the writable layer in the overlay disk using overlayfs, as
+/// the VM does, so that changes end up in the writable layer.
+pub fn with_mounted_root<T, E, F>(base_disk: &Path, overlay_disk: &Path, file_system: &FileSystem, function: F) -> Result<T, E>
+    where
+        E: From<CommandError> + From<std::io::Error>,
+        F: FnOnce(&Path) -> Result<T, E> {
+    if !file_system.is_read_only() {
+        return with_mounted_image(overlay_disk, function);
+    }
+
+    let lower_path = helpers::temp_filename("-lower");
+    let merged_path = helpers::temp_filename("-merged");
+    std::fs::create_dir(&lower_path)?;
+    std::fs::create_dir(&merged_path)?;
+
+    let result = (|| {
+        let lower_disk = MountedDisk::mount(base_disk.to_str().unwrap(), Some(file_system), true, &lower_path)?;
+
+        let result = with_mounted_image(overlay_disk, |layer_path| {
+            let (upper_path, work_path) = overlay_layer_paths(layer_path);
+            run_command("sudo", ["-S", "mkdir", "-p", upper_path.to_str().unwrap(), work_path.to_str().unwrap()])?;
+
+            let options = format!(
+                "lowerdir={},upperdir={},workdir={}",
+                lower_path.display(),
+                upper_path.display(),
+                work_path.display()
+            );
+            run_command("sudo", ["-S", "mount", "-t", "overlay", "overlay", "-o", &options, merged_path.to_str().unwrap()])?;
+
+            let result = function(&merged_path);
+            run_command("sudo", ["-S", "umount", merged_path.to_str().unwrap()])?;
+            result
+        });
+
+        lower_disk.unmount()?;
+        result
+    })();
+
+    std::fs::remove_dir(&lower_path)?;
+    std::fs::remove_dir(&merged_path)?;
+    result
+}
+
+/// Runs the function with a writable view of the directory, whose changes are discarded afterwards. This makes it
+/// possible to add files to a disk created from a directory that is shared with other disks.
+pub fn with_staged_directory<T, E, F>(directory: &Path, function: F) -> Result<T, E>
+    where
+        E: From<CommandError> + From<std::io::Error>,
+        F: FnOnce(&Path) -> Result<T, E> {
+    let layer_path = helpers::temp_filename("-stage");
+    let merged_path = layer_path.join("merged");
+    let (upper_path, work_path) = overlay_layer_paths(&layer_path);
+    for path in [&merged_path, &upper_path, &work_path] {
+        std::fs::create_dir_all(path)?;
+    }
+
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        directory.canonicalize()?.display(),
+        upper_path.display(),
+        work_path.display()
+    );
+
+    let result = run_command("sudo", ["-S", "mount", "-t", "overlay", "overlay", "-o", &options, merged_path.to_str().unwrap()])
+        .map_err(E::from)
+        .and_then(|_| {
+            let result = function(&merged_path);
+            run_command("sudo", ["-S", "umount", merged_path.to_str().unwrap()])?;
+            result
+        });
+
+    // The changes are owned by root
+    run_command("sudo", ["-S", "rm", "-rf", layer_path.to_str().unwrap()])?;
+    result
+}
+
+/// The directories of the writable layer of a read-only root file system, which must match those used by the init.
+fn overlay_layer_paths(layer_path: &Path) -> (PathBuf, PathBuf) {
+    (layer_path.join("upper"), layer_path.join("work"))
+}
+
 pub fn create_copy_on_write_image(disk_file: &Path, backing_file: &Path) -> Result<(), DiskCreateError> {
     if disk_file.exists() {
         return Err(DiskCreateError::DiskAlreadyExists);
@@ -85,10 +523,11 @@ pub fn create_copy_on_write_image(disk_file: &Path, backing_file: &Path) -> Resu
         return Err(DiskCreateError::BackingFileNotExist);
     }
 
+    let backing_file = backing_file.canonicalize().map_err(DiskCreateError::IO)?;
     run_command(
         "qemu-img",
-        &["create", "-f", "qcow2", "-o", &format!("backing_file={}", backing_file.canonicalize().unwrap().to_str().unwrap()), disk_file.to_str().unwrap()]
-    ).map_err(|_| DiskCreateError::FailedCreate)?;
+        ["create", "-f", "qcow2", "-o", &format!("backing_file={}", backing_file.to_str().unwrap()), disk_file.to_str().unwrap()]
+    ).map_err(DiskCreateError::FailedCreate)?;
 
     Ok(())
 }
@@ -96,31 +535,97 @@ pub fn create_copy_on_write_image(disk_file: &Path, backing_file: &Path) -> Resu
 #[derive(Debug)]
 pub struct DiskInfo {
     pub format: DiskFormat,
-    pub backing_file: Option<String>
+    pub backing_file: Option<String>,
+    pub virtual_size_in_bytes: u64
 }
 
 impl DiskInfo {
-    pub fn for_disk_file(disk: &Path) -> Option<DiskInfo> {
-        let output = run_command("qemu-img", &["info", disk.to_str().unwrap()]).ok()?;
+    pub fn for_disk_file(disk: &Path) -> Result<DiskInfo, DiskCreateError> {
+        let output = run_command("qemu-img", ["info", disk.to_str().unwrap()]).map_err(DiskCreateError::FailedInfo)?;
 
         let mut format = None;
         let mut backing_file = None;
+        let mut virtual_size_in_bytes = 0;
 
+        let format_regex = Regex::new("file format: (.*)").unwrap();
+        let backing_file_regex = Regex::new("backing file: (.*)").unwrap();
+        let virtual_size_regex = Regex::new(r"virtual size: .*\((\d+) bytes\)").unwrap();
         for line in output.lines() {
-            if let Some(regex_match) = Regex::new("file format: (.*)").unwrap().captures(line) {
+            if let Some(regex_match) = format_regex.captures(line) {
                 match regex_match.get(1).unwrap().as_str() {
                     "raw" => { format = Some(DiskFormat::Raw); },
                     "qcow2" => { format = Some(DiskFormat::Qcow2); }
                     _ => {}
                 }
-            } else if let Some(regex_match) = Regex::new("backing file: (.*)").unwrap().captures(line) {
+            } else if let Some(regex_match) = backing_file_regex.captures(line) {
                 backing_file = Some(regex_match.get(1).unwrap().as_str().to_owned());
+            } else if let Some(regex_match) = virtual_size_regex.captures(line) {
+                virtual_size_in_bytes = regex_match.get(1).unwrap().as_str().parse().unwrap_or(0);
             }
         }
 
-        Some(DiskInfo {
-            format: format?,
-            backing_file
+        Ok(DiskInfo {
+            format: format.ok_or_else(|| DiskCreateError::UnknownFormat(disk.display().to_string()))?,
+            backing_file,
+            virtual_size_in_bytes
         })
     }
-}
\ No newline at end of file
+}
+
+/// Grows the disk image to the given size. The file system on it is grown by the VM when it boots, except for XFS,
+/// which systemd can't grow and so is grown here.
+pub fn grow_image(disk_file: &Path, size_in_bytes: u64, file_system: &FileSystem) -> Result<(), DiskCreateError> {
+    let disk_info = DiskInfo::for_disk_file(disk_file)?;
+    if size_in_bytes < disk_info.virtual_size_in_bytes {
+        return Err(DiskCreateError::CannotShrink { size_in_bytes: disk_info.virtual_size_in_bytes, requested_bytes: size_in_bytes });
+    }
+
+    if size_in_bytes > disk_info.virtual_size_in_bytes {
+        run_command(
+            "qemu-img",
+            ["resize", "-f", &disk_info.format.to_string(), disk_file.to_str().unwrap(), &size_in_bytes.to_string()]
+        ).map_err(DiskCreateError::FailedCreate)?;
+
+        if *file_system == FileSystem::Xfs {
+            with_mounted_image(disk_file, |mount_path| {
+                run_command("sudo", ["-S", "xfs_growfs", mount_path.to_str().unwrap()])?;
+                Ok::<_, DiskCreateError>(())
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_size() {
+        let usage = DirectoryUsage { size_in_bytes: 760 * MEGABYTE, inodes: 20_000 };
+        assert_eq!(1128 * MEGABYTE, usage.disk_size(FileSystem::Ext4, 25));
+        assert_eq!(1078 * MEGABYTE, usage.disk_size(FileSystem::Xfs, 25));
+
+        // Many small files need more inodes than ext4 creates for the space they use
+        let usage = DirectoryUsage { size_in_bytes: 400 * MEGABYTE, inodes: 50_000 };
+        assert_eq!(1156 * MEGABYTE, usage.disk_size(FileSystem::Ext4, 25));
+        assert_eq!(628 * MEGABYTE, usage.disk_size(FileSystem::Btrfs, 25));
+    }
+
+    #[test]
+    fn test_choose_disk_size() {
+        let usage = DirectoryUsage { size_in_bytes: 760 * MEGABYTE, inodes: 20_000 };
+        assert_eq!(1128 * MEGABYTE, usage.choose_disk_size(None, FileSystem::Ext4, 25).unwrap());
+
+        // A requested size only has to fit the content, without the headroom
+        assert_eq!(928 * MEGABYTE, usage.choose_disk_size(Some(928 * MEGABYTE), FileSystem::Ext4, 25).unwrap());
+        match usage.choose_disk_size(Some(927 * MEGABYTE), FileSystem::Ext4, 25) {
+            Err(DiskCreateError::DiskTooSmall { requested_bytes, required_bytes }) => {
+                assert_eq!(927 * MEGABYTE, requested_bytes);
+                assert_eq!(928 * MEGABYTE, required_bytes);
+            }
+            result => panic!("Expected DiskTooSmall, got {:?}", result)
+        }
+    }
+}
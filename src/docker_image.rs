@@ -1,21 +1,50 @@
 use std::path::{Path, PathBuf};
 
-use crate::helpers::run_command;
+use crate::helpers::{run_command, CommandError};
 use crate::helpers;
+use crate::entrypoint::{Entrypoint, ImageConfig};
 
 #[derive(Debug)]
 pub enum DockerImageError {
     IO(std::io::Error),
-    ImageNotFound,
+    ImageNotFound(String),
     DestinationExist,
-    FailedToExtract(String),
-    FailedToBuild
+    FailedToExtract(CommandError),
+    FailedToBuild(Option<i32>),
+    FailedToAddGuestAgent(CommandError),
+    InvalidConfig(serde_json::Error)
+}
+
+impl std::fmt::Display for DockerImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DockerImageError::IO(err) => write!(f, "I/O error: {}", err),
+            DockerImageError::ImageNotFound(image) => write!(f, "Docker image {} not found, build or pull it first", image),
+            DockerImageError::DestinationExist => write!(f, "Destination already exists"),
+            DockerImageError::FailedToExtract(err) => write!(f, "Failed to extract image: {}", err),
+            DockerImageError::FailedToBuild(Some(exit_code)) => write!(f, "docker build failed with exit code {}", exit_code),
+            DockerImageError::FailedToBuild(None) => write!(f, "Failed to run docker build"),
+            DockerImageError::FailedToAddGuestAgent(err) => write!(f, "Failed to add qemu-guest-agent to the image: {}", err),
+            DockerImageError::InvalidConfig(err) => write!(f, "Failed to parse the image config: {}", err)
+        }
+    }
+}
+
+fn image_hash(image_id: &str) -> Result<String, DockerImageError> {
+    let image_hash = run_command("docker", ["inspect", image_id, "--format={{ .Id }}"])
+        .map_err(|_| DockerImageError::ImageNotFound(image_id.to_owned()))?;
+    let image_hash = image_hash.trim();
+    Ok(image_hash.strip_prefix("sha256:").unwrap_or(image_hash).to_owned())
+}
+
+pub fn inspect_config(image_id: &str) -> Result<ImageConfig, DockerImageError> {
+    let config = run_command("docker", ["inspect", image_id, "--format={{ json .Config }}"])
+        .map_err(|_| DockerImageError::ImageNotFound(image_id.to_owned()))?;
+    serde_json::from_str(&config).map_err(DockerImageError::InvalidConfig)
 }
 
 pub fn try_extract_image(image_id: &str, destination_folder: &Path) -> Result<PathBuf, DockerImageError> {
-    let image_hash = run_command("docker", &["inspect", image_id, "--format={{ .Id }}"])
-        .map_err(|_| DockerImageError::ImageNotFound)?;
-    let image_hash = image_hash.trim().split("sha256:").skip(1).next().unwrap().to_owned();
+    let image_hash = image_hash(image_id)?;
 
     let destination = destination_folder.join(image_hash);
 
@@ -33,7 +62,7 @@ pub fn try_extract_image(image_id: &str, destination_folder: &Path) -> Result<Pa
 pub fn extract_image_filesystem(image_id: &str, destination: &Path) -> Result<(), DockerImageError> {
     if let Some(parent) = destination.parent() {
         if !parent.exists() {
-            std::fs::create_dir(parent).map_err(|err| DockerImageError::IO(err))?;
+            std::fs::create_dir(parent).map_err(DockerImageError::IO)?;
         }
     }
 
@@ -41,10 +70,15 @@ pub fn extract_image_filesystem(image_id: &str, destination: &Path) -> Result<()
         return Err(DockerImageError::DestinationExist);
     }
 
-    std::fs::create_dir(destination).map_err(|err| DockerImageError::IO(err))?;
+    std::fs::create_dir(destination).map_err(DockerImageError::IO)?;
 
-    let container_id = run_command("docker", &["create", image_id])
-        .map_err(|_| DockerImageError::ImageNotFound)?;
+    let container_id = match run_command("docker", ["create", image_id]) {
+        Ok(container_id) => container_id,
+        Err(err) => {
+            std::fs::remove_dir(destination).map_err(DockerImageError::IO)?;
+            return Err(DockerImageError::FailedToExtract(err));
+        }
+    };
     let container_id = container_id.trim();
 
     let tmp_export_path = helpers::temp_filename(".tar");
@@ -53,39 +87,41 @@ pub fn extract_image_filesystem(image_id: &str, destination: &Path) -> Result<()
     let destination_str = destination.to_str().unwrap();
 
     let mut results = Vec::new();
-    results.push(run_command("docker", &["export", &container_id, "--output", tmp_export_path_str])
+    results.push(run_command("docker", ["export", container_id, "--output", tmp_export_path_str])
         .map(|_| ())
-        .map_err(|err| DockerImageError::FailedToExtract(err))
+        .map_err(DockerImageError::FailedToExtract)
     );
 
     match results.last() {
         Some(last) if last.is_ok() => {
-            results.push(run_command("sudo", &["-S", "tar", "--same-owner", "-xvf", tmp_export_path_str, "--directory", destination_str])
+            results.push(run_command("sudo", ["-S", "tar", "--same-owner", "-xvf", tmp_export_path_str, "--directory", destination_str])
                 .map(|_| ())
-                .map_err(|err| DockerImageError::FailedToExtract(err))
+                .map_err(DockerImageError::FailedToExtract)
             );
 
             // Docker messes with /etc/resolv.conf. We re-creates the symlink with what systemd-resolved updates
-            results.push(run_command("sudo", &["-S", "bash", "-c", &format!("rm -f {root_dir}/etc/resolv.conf ; ln -s /run/systemd/resolve/resolv.conf {root_dir}/etc/resolv.conf", root_dir = destination_str)])
+            results.push(run_command("sudo", ["-S", "bash", "-c", &format!("rm -f {root_dir}/etc/resolv.conf ; ln -s /run/systemd/resolve/resolv.conf {root_dir}/etc/resolv.conf", root_dir = destination_str)])
                 .map(|_| ())
-                .map_err(|err| DockerImageError::FailedToExtract(err))
+                .map_err(DockerImageError::FailedToExtract)
             );
+
+            results.push(install_entrypoint(image_id, destination));
         }
         _ => {}
     }
 
-    results.push(run_command("docker", &["rm", &container_id])
+    results.push(run_command("docker", ["rm", container_id])
         .map(|_| ())
-        .map_err(|err| DockerImageError::FailedToExtract(err))
+        .map_err(DockerImageError::FailedToExtract)
     );
 
     results.push(std::fs::remove_file(tmp_export_path)
-        .map_err(|err| DockerImageError::IO(err))
+        .map_err(DockerImageError::IO)
     );
 
     for result in results {
         if let Err(err) = result {
-            std::fs::remove_dir_all(destination).map_err(|err| DockerImageError::IO(err))?;
+            std::fs::remove_dir_all(destination).map_err(DockerImageError::IO)?;
             return Err(err);
         }
     }
@@ -93,18 +129,73 @@ pub fn extract_image_filesystem(image_id: &str, destination: &Path) -> Result<()
     Ok(())
 }
 
+/// Makes application images run their command on boot, like when running a container.
+fn install_entrypoint(image_id: &str, root_dir: &Path) -> Result<(), DockerImageError> {
+    let entrypoint = Entrypoint::from_image_config(&inspect_config(image_id)?);
+    if !entrypoint.is_service() {
+        return Ok(());
+    }
+
+    println!("Running '{}' on boot", entrypoint.command.join(" "));
+    entrypoint.install_systemd_unit(root_dir).map_err(DockerImageError::FailedToExtract)
+}
+
+/// Builds an image on top of the given one with qemu-guest-agent installed and enabled, unless the image already has it.
+/// Returns the tag of the new image, which is extracted like any other image.
+pub fn add_guest_agent(image_id: &str) -> Result<String, DockerImageError> {
+    let tag = format!("docker-on-kvm-guest-agent:{}", image_hash(image_id)?);
+
+    let user = run_command("docker", ["inspect", image_id, "--format={{ .Config.User }}"])
+        .map_err(|_| DockerImageError::ImageNotFound(image_id.to_owned()))?;
+    let user = user.trim();
+
+    let mut dockerfile = format!(r#"FROM {image}
+USER root
+RUN if ! command -v qemu-ga > /dev/null; then \
+        if command -v apt-get > /dev/null; then apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y qemu-guest-agent && rm -rf /var/lib/apt/lists/*; \
+        elif command -v dnf > /dev/null; then dnf install -y qemu-guest-agent; \
+        elif command -v yum > /dev/null; then yum install -y qemu-guest-agent; \
+        elif command -v apk > /dev/null; then apk add --no-cache qemu-guest-agent; \
+        else echo "No supported package manager to install qemu-guest-agent with" && exit 1; fi; \
+    fi && \
+    for unit_dir in /lib/systemd/system /usr/lib/systemd/system; do \
+        if [ -f $unit_dir/qemu-guest-agent.service ]; then \
+            mkdir -p /etc/systemd/system/multi-user.target.wants && \
+            ln -sf $unit_dir/qemu-guest-agent.service /etc/systemd/system/multi-user.target.wants/qemu-guest-agent.service; \
+        fi; \
+    done
+"#, image = image_id);
+
+    if !user.is_empty() {
+        dockerfile += &format!("USER {}\n", user);
+    }
+
+    // Build with an empty context, as nothing is copied into the image
+    let context_dir = helpers::temp_filename("-context");
+    std::fs::create_dir(&context_dir).map_err(DockerImageError::IO)?;
+    let dockerfile_path = context_dir.join("Dockerfile");
+    std::fs::write(&dockerfile_path, dockerfile).map_err(DockerImageError::IO)?;
+
+    println!("Adding qemu-guest-agent to image {}", image_id);
+    let result = run_command("docker", ["build", "-t", &tag, context_dir.to_str().unwrap()]);
+    std::fs::remove_dir_all(&context_dir).map_err(DockerImageError::IO)?;
+    result.map_err(DockerImageError::FailedToAddGuestAgent)?;
+
+    Ok(tag)
+}
+
 pub fn build(filename: &Path, tag: &str) -> Result<(), DockerImageError> {
     let mut command = std::process::Command::new("docker");
     command
         .env("LANG", "en")
-        .args(&["build", "-t", tag, "-f", filename.to_str().unwrap(), "."]);
+        .args(["build", "-t", tag, "-f", filename.to_str().unwrap(), "."]);
 
-    let mut child = command.spawn().map_err(|_| DockerImageError::FailedToBuild)?;
-    let status = child.wait().map_err(|_| DockerImageError::FailedToBuild)?;
+    let mut child = command.spawn().map_err(|_| DockerImageError::FailedToBuild(None))?;
+    let status = child.wait().map_err(|_| DockerImageError::FailedToBuild(None))?;
 
     if status.success() {
         Ok(())
     } else {
-        Err(DockerImageError::FailedToBuild)
+        Err(DockerImageError::FailedToBuild(status.code()))
     }
 }
\ No newline at end of file
@@ -0,0 +1,641 @@
+use std::io::Write;
+
+use xml::writer::{EmitterConfig, EventWriter, XmlEvent};
+
+pub type XmlResult = xml::writer::Result<()>;
+
+/// Thin wrapper around the xml-rs event writer that takes care of element/attribute bookkeeping.
+pub struct XmlWriter<W: Write> {
+    writer: EventWriter<W>
+}
+
+impl<W: Write> XmlWriter<W> {
+    pub fn new(sink: W) -> XmlWriter<W> {
+        XmlWriter {
+            writer: EmitterConfig::new()
+                .perform_indent(true)
+                .write_document_declaration(false)
+                .create_writer(sink)
+        }
+    }
+
+    pub fn start(&mut self, name: &str, attributes: &[(&str, &str)]) -> XmlResult {
+        let mut element = XmlEvent::start_element(name);
+        for (attribute_name, attribute_value) in attributes {
+            element = element.attr(*attribute_name, attribute_value);
+        }
+
+        self.writer.write(element)
+    }
+
+    pub fn end(&mut self) -> XmlResult {
+        self.writer.write(XmlEvent::end_element())
+    }
+
+    pub fn text(&mut self, text: &str) -> XmlResult {
+        self.writer.write(XmlEvent::characters(text))
+    }
+
+    pub fn empty_element(&mut self, name: &str, attributes: &[(&str, &str)]) -> XmlResult {
+        self.start(name, attributes)?;
+        self.end()
+    }
+
+    pub fn text_element(&mut self, name: &str, attributes: &[(&str, &str)], text: &str) -> XmlResult {
+        self.start(name, attributes)?;
+        self.text(text)?;
+        self.end()
+    }
+}
+
+pub trait WriteXml {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult;
+
+    fn to_xml(&self) -> String {
+        let mut buffer = Vec::new();
+        // Writing to a Vec can only fail if the element structure is invalid, which the types prevent
+        self.write_xml(&mut XmlWriter::new(&mut buffer)).expect("Failed to write XML");
+        String::from_utf8(buffer).expect("XML writer produced invalid UTF-8")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DomainDefinition {
+    pub domain_type: String,
+    pub name: String,
+    pub uuid: Option<String>,
+    pub memory_in_bytes: u64,
+    pub num_cpus: u64,
+    /// Backs the memory with shared memory, which virtiofs requires.
+    pub shared_memory: bool,
+    pub os: OsSection,
+    pub features: Vec<Feature>,
+    pub cpu_mode: String,
+    pub clock: Clock,
+    pub on_poweroff: String,
+    pub on_reboot: String,
+    pub on_crash: String,
+    pub devices: Vec<Device>
+}
+
+impl DomainDefinition {
+    pub fn add_device(&mut self, device: Device) {
+        self.devices.push(device);
+    }
+
+    /// Removes all devices matching the predicate.
+    pub fn remove_devices<F: Fn(&Device) -> bool>(&mut self, predicate: F) {
+        self.devices.retain(|device| !predicate(device));
+    }
+}
+
+impl WriteXml for DomainDefinition {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        writer.start("domain", &[("type", &self.domain_type)])?;
+        writer.text_element("name", &[], &self.name)?;
+        if let Some(uuid) = self.uuid.as_ref() {
+            writer.text_element("uuid", &[], uuid)?;
+        }
+
+        let memory = self.memory_in_bytes.to_string();
+        writer.text_element("memory", &[("unit", "B")], &memory)?;
+        writer.text_element("currentMemory", &[("unit", "B")], &memory)?;
+        writer.text_element("vcpu", &[("placement", "static")], &self.num_cpus.to_string())?;
+
+        if self.shared_memory {
+            writer.start("memoryBacking", &[])?;
+            writer.empty_element("source", &[("type", "memfd")])?;
+            writer.empty_element("access", &[("mode", "shared")])?;
+            writer.end()?;
+        }
+
+        self.os.write_xml(writer)?;
+
+        writer.start("features", &[])?;
+        for feature in &self.features {
+            feature.write_xml(writer)?;
+        }
+        writer.end()?;
+
+        writer.empty_element("cpu", &[("mode", &self.cpu_mode), ("check", "partial")])?;
+        self.clock.write_xml(writer)?;
+
+        writer.text_element("on_poweroff", &[], &self.on_poweroff)?;
+        writer.text_element("on_reboot", &[], &self.on_reboot)?;
+        writer.text_element("on_crash", &[], &self.on_crash)?;
+
+        writer.start("pm", &[])?;
+        writer.empty_element("suspend-to-mem", &[("enabled", "no")])?;
+        writer.empty_element("suspend-to-disk", &[("enabled", "no")])?;
+        writer.end()?;
+
+        writer.start("devices", &[])?;
+        for device in &self.devices {
+            device.write_xml(writer)?;
+        }
+        writer.end()?;
+
+        writer.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OsSection {
+    pub arch: String,
+    pub machine: Option<String>,
+    pub loader: Option<String>,
+    pub nvram: Option<String>,
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub cmdline: Option<String>,
+    pub boot_device: String
+}
+
+impl WriteXml for OsSection {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        writer.start("os", &[])?;
+
+        match self.machine.as_ref() {
+            Some(machine) => writer.text_element("type", &[("arch", &self.arch), ("machine", machine)], "hvm")?,
+            None => writer.text_element("type", &[("arch", &self.arch)], "hvm")?
+        }
+
+        if let Some(loader) = self.loader.as_ref() {
+            writer.text_element("loader", &[("readonly", "yes"), ("type", "pflash")], loader)?;
+        }
+
+        if let Some(nvram) = self.nvram.as_ref() {
+            writer.text_element("nvram", &[], nvram)?;
+        }
+
+        if let Some(kernel) = self.kernel.as_ref() {
+            writer.text_element("kernel", &[], kernel)?;
+        }
+
+        if let Some(initrd) = self.initrd.as_ref() {
+            writer.text_element("initrd", &[], initrd)?;
+        }
+
+        if let Some(cmdline) = self.cmdline.as_ref() {
+            writer.text_element("cmdline", &[], cmdline)?;
+        }
+
+        writer.empty_element("boot", &[("dev", &self.boot_device)])?;
+        writer.end()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Feature {
+    Acpi,
+    Apic,
+    VmPortOff
+}
+
+impl WriteXml for Feature {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        match self {
+            Feature::Acpi => writer.empty_element("acpi", &[]),
+            Feature::Apic => writer.empty_element("apic", &[]),
+            Feature::VmPortOff => writer.empty_element("vmport", &[("state", "off")])
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Timer {
+    pub name: String,
+    pub tick_policy: Option<String>,
+    pub present: Option<bool>
+}
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    pub offset: String,
+    pub timers: Vec<Timer>
+}
+
+impl WriteXml for Clock {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        writer.start("clock", &[("offset", &self.offset)])?;
+        for timer in &self.timers {
+            let mut attributes = vec![("name", timer.name.as_str())];
+            if let Some(tick_policy) = timer.tick_policy.as_ref() {
+                attributes.push(("tickpolicy", tick_policy));
+            }
+
+            if let Some(present) = timer.present {
+                attributes.push(("present", if present { "yes" } else { "no" }));
+            }
+
+            writer.empty_element("timer", &attributes)?;
+        }
+        writer.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Device {
+    Emulator(String),
+    Disk(DiskDevice),
+    Controller(Controller),
+    Interface(Interface),
+    Serial { port: u32 },
+    Console { port: u32 },
+    Channel(Channel),
+    Input(Input),
+    Graphics(Graphics),
+    Sound { model: String },
+    Video { model: String },
+    RedirDev { bus: String, redirect_type: String },
+    MemBalloon { model: String },
+    Rng { model: String, backend: String },
+    Filesystem(Filesystem)
+}
+
+impl WriteXml for Device {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        match self {
+            Device::Emulator(emulator) => writer.text_element("emulator", &[], emulator),
+            Device::Disk(disk) => disk.write_xml(writer),
+            Device::Controller(controller) => controller.write_xml(writer),
+            Device::Interface(interface) => interface.write_xml(writer),
+            Device::Serial { port } => {
+                writer.start("serial", &[("type", "pty")])?;
+                writer.start("target", &[("type", "isa-serial"), ("port", &port.to_string())])?;
+                writer.empty_element("model", &[("name", "isa-serial")])?;
+                writer.end()?;
+                writer.end()
+            }
+            Device::Console { port } => {
+                writer.start("console", &[("type", "pty")])?;
+                writer.empty_element("target", &[("type", "serial"), ("port", &port.to_string())])?;
+                writer.end()
+            }
+            Device::Channel(channel) => channel.write_xml(writer),
+            Device::Input(input) => input.write_xml(writer),
+            Device::Graphics(graphics) => graphics.write_xml(writer),
+            Device::Sound { model } => writer.empty_element("sound", &[("model", model)]),
+            Device::Video { model } => {
+                writer.start("video", &[])?;
+                writer.empty_element("model", &[("type", model), ("heads", "1"), ("primary", "yes")])?;
+                writer.end()
+            }
+            Device::RedirDev { bus, redirect_type } => writer.empty_element("redirdev", &[("bus", bus), ("type", redirect_type)]),
+            Device::MemBalloon { model } => writer.empty_element("memballoon", &[("model", model)]),
+            Device::Rng { model, backend } => {
+                writer.start("rng", &[("model", model)])?;
+                writer.text_element("backend", &[("model", "random")], backend)?;
+                writer.end()
+            }
+            Device::Filesystem(filesystem) => filesystem.write_xml(writer)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BackingStore {
+    pub format: String,
+    pub source_file: String
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskDevice {
+    pub device: String,
+    pub format: String,
+    pub source_file: String,
+    pub target_dev: String,
+    pub target_bus: String,
+    pub readonly: bool,
+    pub backing_store: Option<BackingStore>
+}
+
+impl WriteXml for DiskDevice {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        writer.start("disk", &[("type", "file"), ("device", &self.device)])?;
+        writer.empty_element("driver", &[("name", "qemu"), ("type", &self.format)])?;
+        writer.empty_element("source", &[("file", &self.source_file)])?;
+        writer.empty_element("target", &[("dev", &self.target_dev), ("bus", &self.target_bus)])?;
+        if self.readonly {
+            writer.empty_element("readonly", &[])?;
+        }
+
+        if let Some(backing_store) = self.backing_store.as_ref() {
+            writer.start("backingStore", &[("type", "file")])?;
+            writer.empty_element("format", &[("type", &backing_store.format)])?;
+            writer.empty_element("source", &[("file", &backing_store.source_file)])?;
+            writer.end()?;
+        }
+
+        writer.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Controller {
+    pub controller_type: String,
+    pub index: u32,
+    pub model: Option<String>
+}
+
+impl WriteXml for Controller {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        let index = self.index.to_string();
+        let mut attributes = vec![("type", self.controller_type.as_str()), ("index", index.as_str())];
+        if let Some(model) = self.model.as_ref() {
+            attributes.push(("model", model));
+        }
+
+        writer.empty_element("controller", &attributes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InterfaceSource {
+    Network(String),
+    Bridge(String),
+    User
+}
+
+#[derive(Debug, Clone)]
+pub struct Interface {
+    pub source: InterfaceSource,
+    pub mac: Option<String>,
+    pub model: String
+}
+
+impl WriteXml for Interface {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        match &self.source {
+            InterfaceSource::Network(network) => {
+                writer.start("interface", &[("type", "network")])?;
+                writer.empty_element("source", &[("network", network)])?;
+            }
+            InterfaceSource::Bridge(bridge) => {
+                writer.start("interface", &[("type", "bridge")])?;
+                writer.empty_element("source", &[("bridge", bridge)])?;
+            }
+            InterfaceSource::User => {
+                writer.start("interface", &[("type", "user")])?;
+            }
+        }
+
+        if let Some(mac) = self.mac.as_ref() {
+            writer.empty_element("mac", &[("address", mac)])?;
+        }
+
+        writer.empty_element("model", &[("type", &self.model)])?;
+        writer.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub channel_type: String,
+    pub target_name: String
+}
+
+impl WriteXml for Channel {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        writer.start("channel", &[("type", &self.channel_type)])?;
+        writer.empty_element("target", &[("type", "virtio"), ("name", &self.target_name)])?;
+        writer.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Input {
+    pub input_type: String,
+    pub bus: String
+}
+
+impl WriteXml for Input {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        writer.empty_element("input", &[("type", &self.input_type), ("bus", &self.bus)])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Graphics {
+    Spice
+}
+
+impl WriteXml for Graphics {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        match self {
+            Graphics::Spice => {
+                writer.start("graphics", &[("type", "spice"), ("autoport", "yes")])?;
+                writer.empty_element("listen", &[("type", "address")])?;
+                writer.empty_element("image", &[("compression", "off")])?;
+                writer.end()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilesystemDriver {
+    VirtioFs,
+    /// 9p (virtio-9p), which the QEMU process serves itself.
+    Path
+}
+
+/// A host directory shared with the guest, which mounts it using the target tag.
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    pub driver: FilesystemDriver,
+    pub source_dir: String,
+    pub target_tag: String,
+    pub readonly: bool
+}
+
+impl WriteXml for Filesystem {
+    fn write_xml<W: Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult {
+        writer.start("filesystem", &[("type", "mount"), ("accessmode", "passthrough")])?;
+        match self.driver {
+            FilesystemDriver::VirtioFs => writer.empty_element("driver", &[("type", "virtiofs")])?,
+            FilesystemDriver::Path => writer.empty_element("driver", &[("type", "path")])?
+        }
+
+        writer.empty_element("source", &[("dir", &self.source_dir)])?;
+        writer.empty_element("target", &[("dir", &self.target_tag)])?;
+
+        // virtiofs does not support read-only shares, so those are only mounted read-only in the guest
+        if self.readonly && self.driver == FilesystemDriver::Path {
+            writer.empty_element("readonly", &[])?;
+        }
+
+        writer.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_definition() -> DomainDefinition {
+        DomainDefinition {
+            domain_type: "kvm".to_owned(),
+            name: "test".to_owned(),
+            uuid: Some("0e4a7ee1d4a1483a8e2e5d2b5d3f7b21".to_owned()),
+            memory_in_bytes: 2048 * 1024 * 1024,
+            num_cpus: 2,
+            shared_memory: false,
+            os: OsSection {
+                arch: "x86_64".to_owned(),
+                machine: Some("q35".to_owned()),
+                loader: Some("/usr/share/OVMF/OVMF_CODE.fd".to_owned()),
+                nvram: Some("/var/lib/libvirt/qemu/nvram/test_VARS.fd".to_owned()),
+                kernel: Some("/boot/vmlinuz".to_owned()),
+                initrd: Some("/boot/initrd.img".to_owned()),
+                cmdline: Some("root=/dev/vda rw".to_owned()),
+                boot_device: "hd".to_owned()
+            },
+            features: vec![Feature::Acpi, Feature::Apic],
+            cpu_mode: "host-model".to_owned(),
+            clock: Clock {
+                offset: "utc".to_owned(),
+                timers: vec![Timer { name: "hpet".to_owned(), tick_policy: None, present: Some(false) }]
+            },
+            on_poweroff: "destroy".to_owned(),
+            on_reboot: "restart".to_owned(),
+            on_crash: "destroy".to_owned(),
+            devices: vec![
+                Device::Emulator("/usr/bin/qemu-system-x86_64".to_owned()),
+                Device::Disk(DiskDevice {
+                    device: "disk".to_owned(),
+                    format: "qcow2".to_owned(),
+                    source_file: "/disks/test.qcow2".to_owned(),
+                    target_dev: "vda".to_owned(),
+                    target_bus: "virtio".to_owned(),
+                    readonly: false,
+                    backing_store: Some(BackingStore { format: "raw".to_owned(), source_file: "/disks/base.img".to_owned() })
+                }),
+                Device::Interface(Interface {
+                    source: InterfaceSource::Network("default".to_owned()),
+                    mac: Some("52:54:00:12:34:56".to_owned()),
+                    model: "virtio".to_owned()
+                }),
+                Device::Serial { port: 0 },
+                Device::Graphics(Graphics::Spice)
+            ]
+        }
+    }
+
+    #[test]
+    fn test_render_domain() {
+        let xml = test_definition().to_xml();
+
+        assert!(xml.starts_with(r#"<domain type="kvm">"#));
+        assert!(xml.contains("<name>test</name>"));
+        assert!(xml.contains("<uuid>0e4a7ee1d4a1483a8e2e5d2b5d3f7b21</uuid>"));
+        assert!(xml.contains(r#"<memory unit="B">2147483648</memory>"#));
+        assert!(xml.contains(r#"<vcpu placement="static">2</vcpu>"#));
+        assert!(xml.contains(r#"<type arch="x86_64" machine="q35">hvm</type>"#));
+        assert!(xml.contains(r#"<loader readonly="yes" type="pflash">/usr/share/OVMF/OVMF_CODE.fd</loader>"#));
+        assert!(xml.contains("<cmdline>root=/dev/vda rw</cmdline>"));
+        assert!(xml.contains(r#"<timer name="hpet" present="no" />"#));
+        assert!(xml.contains(r#"<source file="/disks/base.img" />"#));
+        assert!(xml.contains(r#"<interface type="network">"#));
+        assert!(xml.contains(r#"<mac address="52:54:00:12:34:56" />"#));
+        assert!(xml.contains(r#"<graphics type="spice" autoport="yes">"#));
+        assert!(xml.trim_end().ends_with("</domain>"));
+    }
+
+    #[test]
+    fn test_render_escapes() {
+        let mut definition = test_definition();
+        definition.name = "a<b>&\"c\"".to_owned();
+        definition.os.cmdline = Some("init=/bin/sh -c 'echo <hi>'".to_owned());
+
+        let xml = definition.to_xml();
+        assert!(xml.contains("<name>a&lt;b&gt;&amp;\"c\"</name>"));
+        assert!(xml.contains("<cmdline>init=/bin/sh -c 'echo &lt;hi&gt;'</cmdline>"));
+    }
+
+    #[test]
+    fn test_render_without_optional() {
+        let mut definition = test_definition();
+        definition.uuid = None;
+        definition.os.machine = None;
+        definition.os.loader = None;
+        definition.os.nvram = None;
+
+        let xml = definition.to_xml();
+        assert!(!xml.contains("<uuid>"));
+        assert!(!xml.contains("<loader"));
+        assert!(!xml.contains("<nvram"));
+        assert!(xml.contains(r#"<type arch="x86_64">hvm</type>"#));
+    }
+
+    #[test]
+    fn test_add_and_remove_devices() {
+        let mut definition = test_definition();
+        definition.remove_devices(|device| matches!(device, Device::Graphics(_)));
+        definition.add_device(Device::Console { port: 0 });
+
+        let xml = definition.to_xml();
+        assert!(!xml.contains("<graphics"));
+        assert!(xml.contains(r#"<console type="pty">"#));
+        assert!(xml.contains(r#"<target type="serial" port="0" />"#));
+    }
+
+    #[test]
+    fn test_render_interface_without_mac() {
+        let xml = Interface {
+            source: InterfaceSource::Network("isolated".to_owned()),
+            mac: None,
+            model: "virtio".to_owned()
+        }.to_xml();
+        assert!(xml.contains(r#"<interface type="network">"#));
+        assert!(xml.contains(r#"<source network="isolated" />"#));
+        assert!(!xml.contains("<mac"));
+    }
+
+    #[test]
+    fn test_render_bridge_and_user_interfaces() {
+        let bridge = Interface {
+            source: InterfaceSource::Bridge("br0".to_owned()),
+            mac: Some("52:54:00:12:34:57".to_owned()),
+            model: "virtio".to_owned()
+        }.to_xml();
+        assert!(bridge.contains(r#"<interface type="bridge">"#));
+        assert!(bridge.contains(r#"<source bridge="br0" />"#));
+
+        let user = Interface {
+            source: InterfaceSource::User,
+            mac: None,
+            model: "virtio".to_owned()
+        }.to_xml();
+        assert!(user.contains(r#"<interface type="user">"#));
+        assert!(!user.contains("<source"));
+    }
+
+    #[test]
+    fn test_render_shared_folders() {
+        let mut definition = test_definition();
+        definition.shared_memory = true;
+        definition.add_device(Device::Filesystem(Filesystem {
+            driver: FilesystemDriver::VirtioFs,
+            source_dir: "/home/user/src".to_owned(),
+            target_tag: "mount0".to_owned(),
+            readonly: true
+        }));
+
+        let xml = definition.to_xml();
+        assert!(xml.contains(r#"<source type="memfd" />"#));
+        assert!(xml.contains(r#"<access mode="shared" />"#));
+        assert!(xml.contains(r#"<driver type="virtiofs" />"#));
+        assert!(xml.contains(r#"<source dir="/home/user/src" />"#));
+        assert!(xml.contains(r#"<target dir="mount0" />"#));
+
+        let nine_p = Filesystem {
+            driver: FilesystemDriver::Path,
+            source_dir: "/home/user/src".to_owned(),
+            target_tag: "mount0".to_owned(),
+            readonly: true
+        }.to_xml();
+        assert!(nine_p.contains(r#"<driver type="path" />"#));
+        assert!(nine_p.contains("<readonly />"));
+    }
+}
@@ -0,0 +1,433 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::Error;
+use crate::health::HealthConfig;
+use crate::helpers::{self, run_command, CommandError};
+
+/// The process configuration of a docker image, as given by docker inspect.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all="PascalCase")]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub working_dir: String,
+    #[serde(default)]
+    pub healthcheck: Option<HealthConfig>
+}
+
+/// Changes to the process configuration of an image given to run, like the options of docker run.
+#[derive(Debug, Clone, Default)]
+pub struct EntrypointOverrides {
+    pub environment: Vec<String>,
+    pub user: Option<String>,
+    pub working_directory: Option<String>,
+    pub command: Vec<String>
+}
+
+impl EntrypointOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.environment.is_empty() && self.user.is_none() && self.working_directory.is_none() && self.command.is_empty()
+    }
+}
+
+impl ImageConfig {
+    /// Applies the overrides like docker run does: variables replace those with the same name, and a command replaces
+    /// the Cmd of the image but keeps its Entrypoint.
+    pub fn with_overrides(&self, overrides: &EntrypointOverrides) -> ImageConfig {
+        let mut config = self.clone();
+
+        let mut environment = config.env.take().unwrap_or_default();
+        for variable in &overrides.environment {
+            let name = variable_name(variable);
+            match environment.iter_mut().find(|existing| variable_name(existing) == name) {
+                Some(existing) => *existing = variable.clone(),
+                None => environment.push(variable.clone())
+            }
+        }
+        config.env = Some(environment);
+
+        if let Some(user) = &overrides.user {
+            config.user = user.clone();
+        }
+
+        if let Some(working_directory) = &overrides.working_directory {
+            config.working_dir = working_directory.clone();
+        }
+
+        if !overrides.command.is_empty() {
+            config.cmd = Some(overrides.command.clone());
+        }
+
+        config
+    }
+}
+
+/// What to run when the VM has booted, like the process of a docker container.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Entrypoint {
+    pub command: Vec<String>,
+    pub environment: Vec<String>,
+    pub user: Option<String>,
+    pub working_directory: Option<String>
+}
+
+const SHELLS: [&str; 5] = ["sh", "bash", "ash", "dash", "zsh"];
+const INITS: [&str; 4] = ["init", "systemd", "/sbin/init", "/lib/systemd/systemd"];
+
+pub const SERVICE_NAME: &str = "docker-entrypoint.service";
+
+/// Where run --init installs the init binary and its configuration in the VM.
+pub const INIT_PATH: &str = "/sbin/docker-on-kvm-init";
+const INIT_BINARY_NAME: &str = "docker-on-kvm-init";
+pub const INIT_CONFIG_PATH: &str = "/etc/docker-on-kvm/init.json";
+/// Where the init writes the exit code of the command before powering off.
+pub const EXIT_CODE_PATH: &str = "/etc/docker-on-kvm/exit-code";
+/// The host kernel modules to load on boot, see overlay::KernelModules.
+pub const MODULES_PATH: &str = "/etc/docker-on-kvm/modules";
+/// Where the init mounts the writable layer of a read-only root file system, which must exist in the image.
+pub const ROOT_LAYER_PATH: &str = "/.docker-on-kvm";
+
+/// The configuration read by the init binary (src/bin/docker-on-kvm-init.rs) when it boots.
+#[derive(Debug, Clone, Serialize)]
+pub struct InitConfig {
+    #[serde(flatten)]
+    pub entrypoint: Entrypoint,
+    pub hostname: String
+}
+
+impl Entrypoint {
+    pub fn from_image_config(config: &ImageConfig) -> Entrypoint {
+        let mut command = config.entrypoint.clone().unwrap_or_default();
+        command.extend(config.cmd.clone().unwrap_or_default());
+
+        Entrypoint {
+            command,
+            environment: config.env.clone().unwrap_or_default(),
+            user: Some(config.user.clone()).filter(|user| !user.is_empty()),
+            working_directory: Some(config.working_dir.clone()).filter(|working_dir| !working_dir.is_empty())
+        }
+    }
+
+    /// Indicates if the command should be run as a service. Images that just start a shell, like the distribution base
+    /// images, or that boot an init system are meant to be used as full systems.
+    pub fn is_service(&self) -> bool {
+        match self.command.as_slice() {
+            [] => false,
+            [command] => {
+                let command_name = Path::new(command).file_name().and_then(|name| name.to_str()).unwrap_or(command);
+                !SHELLS.contains(&command_name) && !INITS.contains(&command.as_str())
+            }
+            [command, ..] => !INITS.contains(&command.as_str())
+        }
+    }
+
+    /// Renders a systemd service that runs the command, powering off the VM when it exits like a stopped container.
+    pub fn systemd_unit(&self) -> String {
+        let mut unit = String::new();
+        unit += "[Unit]\n";
+        unit += "Description=Docker image entrypoint\n";
+        unit += "Wants=network-online.target\n";
+        unit += "After=network-online.target\n";
+        unit += "SuccessAction=poweroff\n";
+        unit += "FailureAction=poweroff\n";
+        unit += "\n";
+
+        unit += "[Service]\n";
+        unit += "Type=simple\n";
+        for variable in &self.environment {
+            unit += &format!("Environment={}\n", quote_unit_environment(variable));
+        }
+
+        if let Some(user) = &self.user {
+            match user.split_once(':') {
+                Some((user, group)) => {
+                    unit += &format!("User={}\nGroup={}\n", user, group);
+                }
+                None => {
+                    unit += &format!("User={}\n", user);
+                }
+            }
+        }
+
+        if let Some(working_directory) = &self.working_directory {
+            unit += &format!("WorkingDirectory={}\n", working_directory);
+        }
+
+        // Relative commands are looked up in the PATH of the environment, like docker does
+        let mut command = self.command.iter().map(|arg| quote_unit_argument(arg)).collect::<Vec<_>>();
+        if !self.command[0].starts_with('/') {
+            command.insert(0, "/usr/bin/env".to_owned());
+        }
+
+        unit += &format!("ExecStart={}\n", command.join(" "));
+        unit += "StandardOutput=journal+console\n";
+        unit += "StandardError=journal+console\n";
+        unit += "\n";
+
+        unit += "[Install]\n";
+        unit += "WantedBy=multi-user.target\n";
+        unit
+    }
+
+    /// Installs and enables the systemd service in the given root file system.
+    pub fn install_systemd_unit(&self, root_dir: &Path) -> Result<(), CommandError> {
+        let system_dir = root_dir.join("etc/systemd/system");
+        let wants_dir = system_dir.join("multi-user.target.wants");
+
+        install_file(self.systemd_unit().as_bytes(), &system_dir.join(SERVICE_NAME), "644")?;
+        run_command("sudo", ["-S", "mkdir", "-p", wants_dir.to_str().unwrap()])?;
+        run_command(
+            "sudo",
+            ["-S", "ln", "-sf", &format!("/etc/systemd/system/{}", SERVICE_NAME), wants_dir.join(SERVICE_NAME).to_str().unwrap()]
+        )?;
+        Ok(())
+    }
+}
+
+impl InitConfig {
+    /// Installs the init binary and this configuration in the given root file system.
+    pub fn install(&self, root_dir: &Path, init_binary: &Path) -> Result<(), CommandError> {
+        let config = serde_json::to_string_pretty(self).unwrap();
+        install_file(config.as_bytes(), &root_dir.join(INIT_CONFIG_PATH.trim_start_matches('/')), "644")?;
+        install_init_binary(root_dir, init_binary)
+    }
+}
+
+/// Finds the init binary to install, which must be statically linked to run in images with another C library or none at
+/// all. Without a given binary, the musl builds and then the one next to the current executable are tried.
+pub fn find_init_binary(init_binary: Option<&Path>) -> Result<PathBuf, Error> {
+    let candidates = match init_binary {
+        Some(init_binary) => vec![init_binary.to_owned()],
+        None => {
+            let current_exe = std::env::current_exe()?;
+            let mut candidates = Vec::new();
+            if let Some(target_dir) = current_exe.parent().and_then(|profile_dir| profile_dir.parent()) {
+                for profile in ["release", "debug"] {
+                    candidates.push(target_dir.join("x86_64-unknown-linux-musl").join(profile).join(INIT_BINARY_NAME));
+                }
+            }
+            candidates.push(current_exe.with_file_name(INIT_BINARY_NAME));
+            candidates
+        }
+    };
+
+    let mut dynamic_binary = None;
+    for candidate in candidates.iter().filter(|candidate| candidate.exists()) {
+        let content = std::fs::read(candidate)?;
+        match elf_has_interpreter(&content) {
+            Some(false) => return Ok(candidate.clone()),
+            _ => { dynamic_binary.get_or_insert_with(|| candidate.clone()); }
+        }
+    }
+
+    match dynamic_binary {
+        Some(init_binary) => Err(Error::InitBinaryNotStatic(init_binary)),
+        None => Err(Error::InitBinaryNotFound(candidates.last().unwrap().clone()))
+    }
+}
+
+/// Returns if the ELF file has a PT_INTERP segment, which dynamically linked executables have, or None if it is not a
+/// little-endian ELF file.
+fn elf_has_interpreter(content: &[u8]) -> Option<bool> {
+    const PT_INTERP: u32 = 3;
+
+    if content.get(0..4)? != b"\x7fELF" || *content.get(5)? != 1 {
+        return None;
+    }
+
+    let read = |offset: usize, size: usize| -> Option<u64> {
+        let bytes = content.get(offset..offset + size)?;
+        Some(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64))
+    };
+
+    // The offsets of e_phoff, e_phentsize and e_phnum for 32-bit and 64-bit files
+    let (program_header_offset, entry_size, entry_count) = match content.get(4)? {
+        1 => (read(0x1c, 4)?, read(0x2a, 2)?, read(0x2c, 2)?),
+        2 => (read(0x20, 8)?, read(0x36, 2)?, read(0x38, 2)?),
+        _ => return None
+    };
+
+    for index in 0..entry_count {
+        let entry_offset = (program_header_offset + index * entry_size) as usize;
+        if read(entry_offset, 4)? as u32 == PT_INTERP {
+            return Some(true);
+        }
+    }
+
+    Some(false)
+}
+
+pub fn install_init_binary(root_dir: &Path, init_binary: &Path) -> Result<(), CommandError> {
+    run_command(
+        "sudo",
+        ["-S", "install", "-D", "-m", "755", init_binary.to_str().unwrap(), root_dir.join(INIT_PATH.trim_start_matches('/')).to_str().unwrap()]
+    )?;
+    Ok(())
+}
+
+/// Prepares an extracted image to become a read-only root file system, which is booted with the init binary that mounts
+/// the writable layer.
+pub fn prepare_read_only_root(root_dir: &Path, init_binary: &Path) -> Result<(), CommandError> {
+    install_init_binary(root_dir, init_binary)?;
+    run_command("sudo", ["-S", "mkdir", "-p", root_dir.join(ROOT_LAYER_PATH.trim_start_matches('/')).to_str().unwrap()])?;
+    Ok(())
+}
+
+/// Writes a file owned by root, creating the parent directories.
+pub fn install_file(content: &[u8], destination: &Path, mode: &str) -> Result<(), CommandError> {
+    let tmp_file = helpers::temp_filename("");
+    std::fs::write(&tmp_file, content).map_err(|err| CommandError {
+        command: format!("write {}", tmp_file.display()),
+        stderr: err.to_string(),
+        exit_code: None
+    })?;
+
+    let result = run_command(
+        "sudo",
+        ["-S", "install", "-D", "-m", mode, tmp_file.to_str().unwrap(), destination.to_str().unwrap()]
+    );
+
+    let _ = std::fs::remove_file(tmp_file);
+    result?;
+    Ok(())
+}
+
+/// Resolves an environment variable given as KEY=VALUE, or as KEY to take the value from the current environment.
+/// Variables given as KEY that are not set are skipped, like in docker.
+pub fn resolve_variable(variable: &str) -> Option<String> {
+    if variable.contains('=') {
+        Some(variable.to_owned())
+    } else {
+        std::env::var(variable).ok().map(|value| format!("{}={}", variable, value))
+    }
+}
+
+/// Reads the environment variables of an env file, with one variable per line and # for comments.
+pub fn read_env_file(path: &Path) -> std::io::Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(
+        content
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(resolve_variable)
+            .collect()
+    )
+}
+
+fn variable_name(variable: &str) -> &str {
+    variable.split_once('=').map(|(name, _)| name).unwrap_or(variable)
+}
+
+/// Quotes an argument for a systemd unit file, escaping specifiers and variable expansions.
+fn quote_unit_argument(argument: &str) -> String {
+    let escaped = argument
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+/// Quotes a variable for an Environment= line of a systemd unit file, which expands specifiers but not variables.
+fn quote_unit_environment(variable: &str) -> String {
+    let escaped = variable
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 64-bit ELF header followed by program headers of the given types.
+    fn elf_file(segment_types: &[u32]) -> Vec<u8> {
+        let mut content = vec![0; 64];
+        content[0..4].copy_from_slice(b"\x7fELF");
+        content[4] = 2;
+        content[5] = 1;
+        content[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        content[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        content[0x38..0x3a].copy_from_slice(&(segment_types.len() as u16).to_le_bytes());
+        for segment_type in segment_types {
+            let mut entry = vec![0; 56];
+            entry[0..4].copy_from_slice(&segment_type.to_le_bytes());
+            content.extend(entry);
+        }
+
+        content
+    }
+
+    #[test]
+    fn test_elf_has_interpreter() {
+        assert_eq!(Some(true), elf_has_interpreter(&elf_file(&[6, 3, 1])));
+        assert_eq!(Some(false), elf_has_interpreter(&elf_file(&[1, 1, 4])));
+        assert_eq!(None, elf_has_interpreter(b"#!/bin/sh\n"));
+        assert_eq!(None, elf_has_interpreter(&elf_file(&[3])[..66]));
+    }
+
+    fn image_config(entrypoint: Option<&[&str]>, cmd: Option<&[&str]>) -> ImageConfig {
+        let to_vec = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect();
+        ImageConfig {
+            entrypoint: entrypoint.map(to_vec),
+            cmd: cmd.map(to_vec),
+            env: Some(vec!["PATH=/usr/local/bin:/usr/bin:/bin".to_owned()]),
+            user: "app:app".to_owned(),
+            working_dir: "/srv".to_owned(),
+            healthcheck: None
+        }
+    }
+
+    #[test]
+    fn test_is_service() {
+        assert!(!Entrypoint::from_image_config(&image_config(None, Some(&["bash"]))).is_service());
+        assert!(!Entrypoint::from_image_config(&image_config(None, Some(&["/bin/sh"]))).is_service());
+        assert!(!Entrypoint::from_image_config(&image_config(None, Some(&["/sbin/init"]))).is_service());
+        assert!(!Entrypoint::from_image_config(&image_config(None, None)).is_service());
+        assert!(Entrypoint::from_image_config(&image_config(None, Some(&["/bin/sh", "-c", "nginx"]))).is_service());
+        assert!(Entrypoint::from_image_config(&image_config(Some(&["docker-entrypoint.sh"]), Some(&["postgres"]))).is_service());
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let mut entrypoint = Entrypoint::from_image_config(&image_config(Some(&["nginx"]), Some(&["-g", "daemon off; pid $PID;"])));
+        entrypoint.environment.push(r#"GREETING=a$b "100%" \n"#.to_owned());
+        let unit = entrypoint.systemd_unit();
+
+        assert!(unit.contains("Environment=\"PATH=/usr/local/bin:/usr/bin:/bin\"\n"));
+        assert!(unit.contains(r#"Environment="GREETING=a$b \"100%%\" \\n""#));
+        assert!(unit.contains("User=app\nGroup=app\n"));
+        assert!(unit.contains("WorkingDirectory=/srv\n"));
+        assert!(unit.contains(r#"ExecStart=/usr/bin/env "nginx" "-g" "daemon off; pid $$PID;""#));
+    }
+
+    #[test]
+    fn test_with_overrides() {
+        let overrides = EntrypointOverrides {
+            environment: vec!["PATH=/opt/bin".to_owned(), "MODE=debug".to_owned(), "PASSWORD=pa$$word".to_owned()],
+            user: Some("root".to_owned()),
+            working_directory: None,
+            command: vec!["mysql".to_owned(), "--version".to_owned()]
+        };
+
+        let config = image_config(Some(&["docker-entrypoint.sh"]), Some(&["mysqld"])).with_overrides(&overrides);
+        let entrypoint = Entrypoint::from_image_config(&config);
+
+        assert_eq!(vec!["docker-entrypoint.sh", "mysql", "--version"], entrypoint.command);
+        assert_eq!(vec!["PATH=/opt/bin", "MODE=debug", "PASSWORD=pa$$word"], entrypoint.environment);
+        assert_eq!(Some("root".to_owned()), entrypoint.user);
+        assert_eq!(Some("/srv".to_owned()), entrypoint.working_directory);
+        assert!(entrypoint.systemd_unit().contains("Environment=\"PASSWORD=pa$$word\"\n"));
+    }
+}
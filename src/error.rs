@@ -0,0 +1,141 @@
+use crate::helpers::CommandError;
+use crate::docker_image::DockerImageError;
+use crate::disk_creator::DiskCreateError;
+use crate::state::StateStoreError;
+use crate::hypervisor::LifecycleError;
+use crate::guest_agent::GuestAgentError;
+use crate::volume::VolumeError;
+use crate::definition::MacAddress;
+
+const INIT_BUILD_COMMAND: &str = "cargo build --release --target x86_64-unknown-linux-musl --bin docker-on-kvm-init";
+
+#[derive(Debug)]
+pub enum Error {
+    Command(CommandError),
+    DockerImage(DockerImageError),
+    DiskCreate(DiskCreateError),
+    StateStore(StateStoreError),
+    Lifecycle(LifecycleError),
+    GuestAgent(GuestAgentError),
+    Volume(VolumeError),
+    Libvirt(virt::error::Error),
+    IO(std::io::Error),
+    NoKernelsFound,
+    KernelNotFound(String),
+    PublishNotSupported,
+    RemotePublishNotSupported,
+    NoGuestAddress(String),
+    InvalidCopy(String),
+    InitBinaryNotFound(std::path::PathBuf),
+    InitBinaryNotStatic(std::path::PathBuf),
+    UserNotFound(String),
+    MacAddressInUse(MacAddress),
+    NotReady { name: String, reason: String }
+}
+
+impl Error {
+    /// The exit code of the command, which is the same for a missing VM whether libvirt or the state store reports it.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Lifecycle(err) => err.exit_code(),
+            Error::StateStore(StateStoreError::NotFound(_)) => 2,
+            Error::Volume(VolumeError::NotFound(_)) => 2,
+            _ => 1
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Command(err) => write!(f, "{}", err),
+            Error::DockerImage(err) => write!(f, "{}", err),
+            Error::DiskCreate(err) => write!(f, "{}", err),
+            Error::StateStore(err) => write!(f, "{}", err),
+            Error::Lifecycle(err) => write!(f, "{}", err),
+            Error::GuestAgent(err) => write!(f, "{}", err),
+            Error::Volume(err) => write!(f, "{}", err),
+            Error::Libvirt(err) => write!(f, "libvirt error: code {}, message: {}", err.code, err.message),
+            Error::IO(err) => write!(f, "I/O error: {}", err),
+            Error::NoKernelsFound => write!(f, "No linux kernels found in /boot"),
+            Error::KernelNotFound(version) => write!(f, "Could not find the linux kernel {}, use list-kernels to get the available ones", version),
+            Error::PublishNotSupported => write!(f, "Publishing ports requires a libvirt network or user-mode network interface"),
+            Error::RemotePublishNotSupported => write!(f, "Publishing ports of a libvirt network interface requires a local connection, use a user-mode network interface instead"),
+            Error::NoGuestAddress(name) => write!(f, "VM {} did not get an IP address", name),
+            Error::InvalidCopy(message) => write!(f, "Invalid copy: {}", message),
+            Error::InitBinaryNotFound(path) => write!(f, "The init binary {} does not exist, build it with {}", path.display(), INIT_BUILD_COMMAND),
+            Error::InitBinaryNotStatic(path) => write!(f, "The init binary {} is not statically linked, build it with {}", path.display(), INIT_BUILD_COMMAND),
+            Error::UserNotFound(user) => write!(f, "The user {} does not exist in the image, use --ssh-user to add the SSH keys to another user", user),
+            Error::MacAddressInUse(mac_address) => write!(f, "The MAC address {} is already used by another VM", mac_address),
+            Error::NotReady { name, reason } => write!(f, "VM {} did not become ready: {}", name, reason)
+        }
+    }
+}
+
+impl From<CommandError> for Error {
+    fn from(err: CommandError) -> Self {
+        Error::Command(err)
+    }
+}
+
+impl From<DockerImageError> for Error {
+    fn from(err: DockerImageError) -> Self {
+        Error::DockerImage(err)
+    }
+}
+
+impl From<DiskCreateError> for Error {
+    fn from(err: DiskCreateError) -> Self {
+        Error::DiskCreate(err)
+    }
+}
+
+impl From<StateStoreError> for Error {
+    fn from(err: StateStoreError) -> Self {
+        Error::StateStore(err)
+    }
+}
+
+impl From<LifecycleError> for Error {
+    fn from(err: LifecycleError) -> Self {
+        Error::Lifecycle(err)
+    }
+}
+
+impl From<GuestAgentError> for Error {
+    fn from(err: GuestAgentError) -> Self {
+        Error::GuestAgent(err)
+    }
+}
+
+impl From<VolumeError> for Error {
+    fn from(err: VolumeError) -> Self {
+        Error::Volume(err)
+    }
+}
+
+impl From<virt::error::Error> for Error {
+    fn from(err: virt::error::Error) -> Self {
+        Error::Libvirt(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(2, Error::from(LifecycleError::NotFound("web".to_owned())).exit_code());
+        assert_eq!(2, Error::from(StateStoreError::NotFound("web".to_owned())).exit_code());
+        assert_eq!(2, Error::from(VolumeError::NotFound("data".to_owned())).exit_code());
+        assert_eq!(3, Error::from(LifecycleError::InvalidState { name: "web".to_owned(), state: "running" }).exit_code());
+        assert_eq!(1, Error::NoKernelsFound.exit_code());
+    }
+}
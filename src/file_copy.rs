@@ -0,0 +1,156 @@
+use std::path::{Path, PathBuf};
+
+use crate::disk_creator;
+use crate::error::Error;
+use crate::guest_agent::{self, GuestAgentError};
+use crate::helpers::{self, run_command};
+use crate::hypervisor;
+use crate::state::{StateStore, VirtualMachineState};
+
+/// A path for cp, either on the host or in a VM given as name:/path like in docker cp.
+#[derive(Debug, Clone)]
+pub enum CopyPath {
+    Host(PathBuf),
+    Vm { name: String, path: String }
+}
+
+impl std::str::FromStr for CopyPath {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split_once(':') {
+            Some((name, path)) if !name.is_empty() && !name.contains('/') => {
+                if !path.starts_with('/') {
+                    return Err(format!("The path in the VM must be absolute: '{}'", text));
+                }
+
+                Ok(CopyPath::Vm { name: name.to_owned(), path: path.to_owned() })
+            }
+            _ => Ok(CopyPath::Host(PathBuf::from(text)))
+        }
+    }
+}
+
+/// Copies files between the host and a VM, preserving ownership. Stopped VMs are copied to and from by mounting the disk
+/// overlay, while running VMs are copied to and from using the guest agent.
+pub fn copy(uri: &str, state_store: &StateStore, source: &CopyPath, destination: &CopyPath) -> Result<(), Error> {
+    let name = match (source, destination) {
+        (CopyPath::Host(_), CopyPath::Vm { name, .. }) | (CopyPath::Vm { name, .. }, CopyPath::Host(_)) => name,
+        _ => return Err(Error::InvalidCopy("exactly one of the paths must be in a VM".to_owned()))
+    };
+
+    let vm_state = state_store.get(name)?;
+    let running = hypervisor::with_connection(uri, |conn| hypervisor::is_running(conn, name))?;
+
+    match (source, destination, running) {
+        (CopyPath::Host(source), CopyPath::Vm { path, .. }, false) => {
+            copy_offline(&vm_state, |mount_path| (source.clone(), guest_path(mount_path, path)))
+        }
+        (CopyPath::Vm { path, .. }, CopyPath::Host(destination), false) => {
+            copy_offline(&vm_state, |mount_path| (guest_path(mount_path, path), destination.clone()))
+        }
+        (CopyPath::Host(source), CopyPath::Vm { path, .. }, true) => copy_to_guest(uri, name, source, path),
+        (CopyPath::Vm { path, .. }, CopyPath::Host(destination), true) => copy_from_guest(uri, name, path, destination),
+        _ => unreachable!()
+    }
+}
+
+fn guest_path(mount_path: &Path, path: &str) -> PathBuf {
+    mount_path.join(path.trim_start_matches('/'))
+}
+
+fn split_path(path: &str) -> Result<(String, String), Error> {
+    let path = Path::new(path);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => Ok((parent.to_str().unwrap().to_owned(), file_name.to_str().unwrap().to_owned())),
+        _ => Err(Error::InvalidCopy(format!("can't copy '{}'", path.display())))
+    }
+}
+
+fn copy_offline<F: FnOnce(&Path) -> (PathBuf, PathBuf)>(vm_state: &VirtualMachineState, paths: F) -> Result<(), Error> {
+    disk_creator::with_mounted_root(&vm_state.base_disk, &vm_state.overlay_disk, &vm_state.filesystem, |mount_path| {
+        let (source, destination) = paths(mount_path);
+        run_command("sudo", ["-S", "cp", "-a", source.to_str().unwrap(), destination.to_str().unwrap()])?;
+        Ok(())
+    })
+}
+
+fn copy_to_guest(uri: &str, name: &str, source: &Path, destination: &str) -> Result<(), Error> {
+    // Like cp, copy into the destination if it is a directory, and otherwise to a file with that name
+    let destination_is_directory = match guest_agent::run(uri, name, "/bin/sh", &shell_args("test -d \"$1\"", &[destination])) {
+        Ok(()) => true,
+        Err(GuestAgentError::ProcessFailed { .. }) => false,
+        Err(err) => return Err(err.into())
+    };
+
+    let (destination_directory, destination_name) = if destination_is_directory {
+        let source_name = source.file_name().ok_or_else(|| Error::InvalidCopy(format!("can't copy '{}'", source.display())))?;
+        (destination.to_owned(), source_name.to_str().unwrap().to_owned())
+    } else {
+        split_path(destination)?
+    };
+
+    // The files are staged under the destination name and sent as an archive to keep ownership and permissions
+    let staging_path = helpers::temp_filename("-staging");
+    let archive_path = helpers::temp_filename(".tar");
+    let guest_archive_path = guest_agent::temp_filename("cp", ".tar");
+    std::fs::create_dir(&staging_path)?;
+
+    let result = (|| -> Result<(), Error> {
+        run_command("sudo", ["-S", "cp", "-a", source.to_str().unwrap(), staging_path.join(&destination_name).to_str().unwrap()])?;
+        run_command(
+            "sudo",
+            ["-S", "tar", "--numeric-owner", "-cf", archive_path.to_str().unwrap(), "-C", staging_path.to_str().unwrap(), &destination_name]
+        )?;
+
+        guest_agent::upload_file(uri, name, &archive_path, &guest_archive_path)?;
+        let extract_result = guest_agent::run(
+            uri,
+            name,
+            "/bin/tar",
+            &["--numeric-owner".to_owned(), "-xpf".to_owned(), guest_archive_path.clone(), "-C".to_owned(), destination_directory.clone()]
+        );
+        guest_agent::run(uri, name, "/bin/rm", &["-f".to_owned(), guest_archive_path.clone()])?;
+        extract_result?;
+        Ok(())
+    })();
+
+    run_command("sudo", ["-S", "rm", "-rf", staging_path.to_str().unwrap(), archive_path.to_str().unwrap()])?;
+    result
+}
+
+fn copy_from_guest(uri: &str, name: &str, source: &str, destination: &Path) -> Result<(), Error> {
+    let (source_directory, source_name) = split_path(source)?;
+
+    let staging_path = helpers::temp_filename("-staging");
+    let archive_path = helpers::temp_filename(".tar");
+    let guest_archive_path = guest_agent::temp_filename("cp", ".tar");
+    std::fs::create_dir(&staging_path)?;
+
+    let result = (|| -> Result<(), Error> {
+        let archive_result = guest_agent::run(
+            uri,
+            name,
+            "/bin/tar",
+            &["--numeric-owner".to_owned(), "-cf".to_owned(), guest_archive_path.clone(), "-C".to_owned(), source_directory, source_name.clone()]
+        ).and_then(|_| guest_agent::download_file(uri, name, &guest_archive_path, &archive_path));
+        guest_agent::run(uri, name, "/bin/rm", &["-f".to_owned(), guest_archive_path.clone()])?;
+        archive_result?;
+
+        run_command(
+            "sudo",
+            ["-S", "tar", "--numeric-owner", "-xpf", archive_path.to_str().unwrap(), "-C", staging_path.to_str().unwrap()]
+        )?;
+        run_command("sudo", ["-S", "cp", "-a", staging_path.join(&source_name).to_str().unwrap(), destination.to_str().unwrap()])?;
+        Ok(())
+    })();
+
+    run_command("sudo", ["-S", "rm", "-rf", staging_path.to_str().unwrap(), archive_path.to_str().unwrap()])?;
+    result
+}
+
+fn shell_args(script: &str, args: &[&str]) -> Vec<String> {
+    let mut shell_args = vec!["-c".to_owned(), script.to_owned(), "sh".to_owned()];
+    shell_args.extend(args.iter().map(|arg| arg.to_string()));
+    shell_args
+}
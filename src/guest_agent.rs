@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use serde_json::{json, Value};
+
+use crate::helpers::{run_command, CommandError};
+use crate::helpers;
+
+#[derive(Debug)]
+pub enum GuestAgentError {
+    Command(CommandError),
+    InvalidResponse(String),
+    ProcessFailed { command: String, exit_code: i32 },
+    IO(std::io::Error)
+}
+
+impl std::fmt::Display for GuestAgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuestAgentError::Command(err) => write!(f, "Guest agent command failed (is qemu-guest-agent running in the VM? See run --guest-agent): {}", err),
+            GuestAgentError::InvalidResponse(response) => write!(f, "Invalid response from the guest agent: {}", response),
+            GuestAgentError::ProcessFailed { command, exit_code } => write!(f, "Command '{}' failed in the guest with exit code {}", command, exit_code),
+            GuestAgentError::IO(err) => write!(f, "I/O error: {}", err)
+        }
+    }
+}
+
+impl From<CommandError> for GuestAgentError {
+    fn from(err: CommandError) -> Self {
+        GuestAgentError::Command(err)
+    }
+}
+
+/// Executes a QEMU guest agent command and returns its result.
+pub fn execute(uri: &str, name: &str, command: &str, arguments: Value) -> Result<Value, GuestAgentError> {
+    let request = json!({ "execute": command, "arguments": arguments });
+    let output = run_command("virsh", ["-c", uri, "qemu-agent-command", name, &request.to_string()])?;
+
+    let response = serde_json::from_str::<Value>(&output)
+        .map_err(|_| GuestAgentError::InvalidResponse(output.trim().to_owned()))?;
+
+    match response.get("return") {
+        Some(result) => Ok(result.clone()),
+        None => Err(GuestAgentError::InvalidResponse(output.trim().to_owned()))
+    }
+}
+
+/// Checks that the guest agent responds.
+pub fn ping(uri: &str, name: &str) -> Result<(), GuestAgentError> {
+    execute(uri, name, "guest-ping", json!({}))?;
+    Ok(())
+}
+
+pub fn open_file(uri: &str, name: &str, path: &str, mode: &str) -> Result<i64, GuestAgentError> {
+    let handle = execute(uri, name, "guest-file-open", json!({ "path": path, "mode": mode }))?;
+    handle.as_i64().ok_or_else(|| GuestAgentError::InvalidResponse(handle.to_string()))
+}
+
+/// Reads the next chunk of the file, returning an empty buffer at the end of the file.
+pub fn read_file(uri: &str, name: &str, handle: i64) -> Result<Vec<u8>, GuestAgentError> {
+    let result = execute(uri, name, "guest-file-read", json!({ "handle": handle, "count": 48 * 1024 }))?;
+    let data = result.get("buf-b64")
+        .and_then(|data| data.as_str())
+        .ok_or_else(|| GuestAgentError::InvalidResponse(result.to_string()))?;
+
+    helpers::base64_decode(data).map_err(GuestAgentError::InvalidResponse)
+}
+
+pub fn write_file(uri: &str, name: &str, handle: i64, data: &[u8]) -> Result<(), GuestAgentError> {
+    execute(uri, name, "guest-file-write", json!({ "handle": handle, "buf-b64": helpers::base64_encode(data) }))?;
+    Ok(())
+}
+
+pub fn close_file(uri: &str, name: &str, handle: i64) -> Result<(), GuestAgentError> {
+    execute(uri, name, "guest-file-close", json!({ "handle": handle }))?;
+    Ok(())
+}
+
+/// Starts a process in the guest and returns its PID.
+pub fn spawn(uri: &str, name: &str, path: &str, args: &[String]) -> Result<i64, GuestAgentError> {
+    let result = execute(uri, name, "guest-exec", json!({ "path": path, "arg": args }))?;
+    result.get("pid")
+        .and_then(|pid| pid.as_i64())
+        .ok_or_else(|| GuestAgentError::InvalidResponse(result.to_string()))
+}
+
+/// Returns the exit code of the process if it has exited. Processes killed by a signal get 128 + the signal, like in a shell.
+pub fn exit_code(uri: &str, name: &str, pid: i64) -> Result<Option<i32>, GuestAgentError> {
+    let status = execute(uri, name, "guest-exec-status", json!({ "pid": pid }))?;
+    if !status.get("exited").and_then(|exited| exited.as_bool()).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    match (status.get("exitcode").and_then(|code| code.as_i64()), status.get("signal").and_then(|signal| signal.as_i64())) {
+        (Some(exit_code), _) => Ok(Some(exit_code as i32)),
+        (None, Some(signal)) => Ok(Some(128 + signal as i32)),
+        (None, None) => Err(GuestAgentError::InvalidResponse(status.to_string()))
+    }
+}
+
+/// Runs a command in the guest without capturing its output, failing if it exits with a non-zero exit code.
+pub fn run(uri: &str, name: &str, path: &str, args: &[String]) -> Result<(), GuestAgentError> {
+    let pid = spawn(uri, name, path, args)?;
+    loop {
+        match exit_code(uri, name, pid)? {
+            Some(0) => return Ok(()),
+            Some(exit_code) => {
+                return Err(GuestAgentError::ProcessFailed {
+                    command: std::iter::once(path.to_owned()).chain(args.iter().cloned()).collect::<Vec<_>>().join(" "),
+                    exit_code
+                });
+            }
+            None => std::thread::sleep(Duration::from_millis(100))
+        }
+    }
+}
+
+/// Copies a file on the host to the guest.
+pub fn upload_file(uri: &str, name: &str, source: &Path, destination: &str) -> Result<(), GuestAgentError> {
+    let mut source = File::open(source).map_err(GuestAgentError::IO)?;
+    let handle = open_file(uri, name, destination, "w")?;
+
+    let mut buffer = vec![0; 48 * 1024];
+    let result = loop {
+        match source.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(count) => {
+                if let Err(err) = write_file(uri, name, handle, &buffer[..count]) {
+                    break Err(err);
+                }
+            }
+            Err(err) => break Err(GuestAgentError::IO(err))
+        }
+    };
+
+    close_file(uri, name, handle)?;
+    result
+}
+
+/// Copies a file in the guest to the host.
+pub fn download_file(uri: &str, name: &str, source: &str, destination: &Path) -> Result<(), GuestAgentError> {
+    let mut destination = File::create(destination).map_err(GuestAgentError::IO)?;
+    let handle = open_file(uri, name, source, "r")?;
+
+    let result = loop {
+        match read_file(uri, name, handle) {
+            Ok(data) if data.is_empty() => break Ok(()),
+            Ok(data) => {
+                if let Err(err) = destination.write_all(&data) {
+                    break Err(GuestAgentError::IO(err));
+                }
+            }
+            Err(err) => break Err(err)
+        }
+    };
+
+    close_file(uri, name, handle)?;
+    result
+}
+
+/// Runs a command in the guest and waits for it to exit, returning the exit code.
+/// The guest agent only returns the output of a process once it has exited, so to stream the output, it is redirected
+/// to files in the guest that are read while the command runs.
+pub fn exec(uri: &str, name: &str, command: &[String]) -> Result<i32, GuestAgentError> {
+    let stdout_file = temp_filename("exec", ".out");
+    let stderr_file = temp_filename("exec", ".err");
+
+    // Create the files before the command starts so that they can be opened for reading
+    for file in [&stdout_file, &stderr_file] {
+        let handle = open_file(uri, name, file, "w")?;
+        close_file(uri, name, handle)?;
+    }
+
+    let stdout_handle = open_file(uri, name, &stdout_file, "r")?;
+    let stderr_handle = open_file(uri, name, &stderr_file, "r")?;
+
+    let mut args = vec![
+        "-c".to_owned(),
+        format!("exec \"$@\" >> {} 2>> {}", stdout_file, stderr_file),
+        "sh".to_owned()
+    ];
+    args.extend(command.iter().cloned());
+
+    let result = spawn(uri, name, "/bin/sh", &args).and_then(|pid| {
+        loop {
+            let exit_code = exit_code(uri, name, pid)?;
+            forward_output(uri, name, stdout_handle, &mut std::io::stdout())?;
+            forward_output(uri, name, stderr_handle, &mut std::io::stderr())?;
+
+            if let Some(exit_code) = exit_code {
+                return Ok(exit_code);
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    });
+
+    close_file(uri, name, stdout_handle)?;
+    close_file(uri, name, stderr_handle)?;
+    spawn(uri, name, "/bin/rm", &["-f".to_owned(), stdout_file, stderr_file])?;
+
+    result
+}
+
+/// A unique filename for a temporary file in the guest.
+pub fn temp_filename(purpose: &str, suffix: &str) -> String {
+    let id: String = thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from).collect();
+    format!("/run/docker-on-kvm-{}-{}{}", purpose, id, suffix)
+}
+
+fn forward_output<W: Write>(uri: &str, name: &str, handle: i64, output: &mut W) -> Result<(), GuestAgentError> {
+    loop {
+        let data = read_file(uri, name, handle)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        output.write_all(&data).map_err(GuestAgentError::IO)?;
+        output.flush().map_err(GuestAgentError::IO)?;
+    }
+}
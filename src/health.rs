@@ -0,0 +1,165 @@
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+use crate::error::Error;
+use crate::guest_agent::{self, GuestAgentError};
+use crate::hypervisor;
+use crate::port_forward::Protocol;
+use crate::state::VirtualMachineState;
+
+/// The HEALTHCHECK of a docker image, as given by docker inspect. The durations are in nanoseconds.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all="PascalCase")]
+pub struct HealthConfig {
+    #[serde(default)]
+    pub test: Vec<String>,
+    #[serde(default)]
+    pub timeout: u64
+}
+
+/// A health check command that is run in the guest through the guest agent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub command: Vec<String>,
+    pub timeout_secs: u64
+}
+
+impl HealthCheck {
+    /// Creates the health check of an image, if it has one that is not disabled.
+    pub fn from_config(config: &HealthConfig) -> Option<HealthCheck> {
+        let command = match config.test.split_first() {
+            Some((kind, command)) if kind == "CMD" && !command.is_empty() => command.to_vec(),
+            Some((kind, command)) if kind == "CMD-SHELL" && !command.is_empty() => {
+                vec!["/bin/sh".to_owned(), "-c".to_owned(), command.join(" ")]
+            }
+            _ => return None
+        };
+
+        // Like docker, the timeout defaults to 30 seconds
+        let timeout_secs = if config.timeout > 0 { (config.timeout / 1_000_000_000).max(1) } else { 30 };
+        Some(HealthCheck { command, timeout_secs })
+    }
+}
+
+/// What makes a VM ready for use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadinessCheck {
+    /// The guest agent responds.
+    Agent,
+    /// The TCP port accepts connections.
+    Port(u16),
+    /// The HEALTHCHECK of the image succeeds.
+    HealthCheck
+}
+
+impl std::fmt::Display for ReadinessCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadinessCheck::Agent => write!(f, "the guest agent to respond"),
+            ReadinessCheck::Port(port) => write!(f, "port {} to open", port),
+            ReadinessCheck::HealthCheck => write!(f, "the health check to succeed")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Health {
+    Healthy,
+    Unhealthy(String)
+}
+
+/// Checks if the VM is ready once.
+pub fn check(uri: &str, vm_state: &VirtualMachineState, readiness_check: ReadinessCheck) -> Health {
+    let result = match readiness_check {
+        ReadinessCheck::Agent => guest_agent::ping(uri, &vm_state.name).map_err(|err| err.to_string()),
+        ReadinessCheck::Port(port) => check_port(uri, vm_state, port),
+        ReadinessCheck::HealthCheck => {
+            match &vm_state.healthcheck {
+                Some(healthcheck) => run_health_check(uri, &vm_state.name, healthcheck),
+                None => Err("the image has no health check".to_owned())
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => Health::Healthy,
+        Err(reason) => Health::Unhealthy(reason)
+    }
+}
+
+/// Waits until the VM is ready, failing if it is not ready within the timeout.
+pub fn wait_until_ready(uri: &str, vm_state: &VirtualMachineState, readiness_check: ReadinessCheck, timeout: Duration) -> Result<(), Error> {
+    println!("Waiting for {}.", readiness_check);
+
+    let start_time = Instant::now();
+    loop {
+        match check(uri, vm_state, readiness_check) {
+            Health::Healthy => return Ok(()),
+            Health::Unhealthy(reason) if start_time.elapsed() >= timeout => {
+                return Err(Error::NotReady { name: vm_state.name.clone(), reason });
+            }
+            Health::Unhealthy(_) => std::thread::sleep(Duration::from_secs(1))
+        }
+    }
+}
+
+fn check_port(uri: &str, vm_state: &VirtualMachineState, port: u16) -> Result<(), String> {
+    // Guests behind user-mode networking are only reachable through their published ports
+    let address = match hypervisor::guest_address(uri, &vm_state.name, &vm_state.network_interfaces) {
+        Some(address) => format!("{}:{}", address, port),
+        None => {
+            let published_port = vm_state.published_ports
+                .iter()
+                .find(|published_port| published_port.mapping.guest_port == port && published_port.mapping.protocol == Protocol::Tcp)
+                .ok_or_else(|| "the VM has no reachable address".to_owned())?;
+            let host_ip = published_port.mapping.host_ip.filter(|host_ip| !host_ip.is_unspecified()).unwrap_or(Ipv4Addr::LOCALHOST);
+            format!("{}:{}", host_ip, published_port.mapping.host_port)
+        }
+    };
+
+    let address = address.parse::<SocketAddr>().map_err(|err| err.to_string())?;
+    TcpStream::connect_timeout(&address, Duration::from_secs(1)).map_err(|err| format!("{}: {}", address, err))?;
+    Ok(())
+}
+
+fn run_health_check(uri: &str, name: &str, healthcheck: &HealthCheck) -> Result<(), String> {
+    let (path, args) = healthcheck.command.split_first().unwrap();
+    let pid = guest_agent::spawn(uri, name, path, args).map_err(|err| err.to_string())?;
+
+    let start_time = Instant::now();
+    loop {
+        match guest_agent::exit_code(uri, name, pid) {
+            Ok(Some(0)) => return Ok(()),
+            Ok(Some(exit_code)) => return Err(GuestAgentError::ProcessFailed { command: healthcheck.command.join(" "), exit_code }.to_string()),
+            Ok(None) if start_time.elapsed() >= Duration::from_secs(healthcheck.timeout_secs) => {
+                return Err(format!("the health check timed out after {} seconds", healthcheck.timeout_secs));
+            }
+            Ok(None) => std::thread::sleep(Duration::from_millis(100)),
+            Err(err) => return Err(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health_config(test: &[&str]) -> HealthConfig {
+        HealthConfig { test: test.iter().map(|part| part.to_string()).collect(), timeout: 5_000_000_000 }
+    }
+
+    #[test]
+    fn test_from_config() {
+        let healthcheck = HealthCheck::from_config(&health_config(&["CMD-SHELL", "curl -f http://localhost/ || exit 1"])).unwrap();
+        assert_eq!(vec!["/bin/sh", "-c", "curl -f http://localhost/ || exit 1"], healthcheck.command);
+        assert_eq!(5, healthcheck.timeout_secs);
+
+        let healthcheck = HealthCheck::from_config(&health_config(&["CMD", "pg_isready", "-U", "postgres"])).unwrap();
+        assert_eq!(vec!["pg_isready", "-U", "postgres"], healthcheck.command);
+
+        assert_eq!(None, HealthCheck::from_config(&health_config(&["NONE"])));
+        assert_eq!(None, HealthCheck::from_config(&health_config(&[])));
+    }
+}
@@ -5,25 +5,62 @@ use std::path::{PathBuf, Path};
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
 
-pub fn run_command<I, S>(command: &str, args: I) -> Result<String, String>
+#[derive(Debug)]
+pub struct CommandError {
+    pub command: String,
+    pub stderr: String,
+    pub exit_code: Option<i32>
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.exit_code {
+            Some(exit_code) => write!(f, "Command '{}' failed with exit code {}", self.command, exit_code)?,
+            None => write!(f, "Command '{}' failed", self.command)?
+        }
+
+        let stderr = self.stderr.trim();
+        if !stderr.is_empty() {
+            write!(f, ": {}", stderr)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn run_command<I, S>(command: &str, args: I) -> Result<String, CommandError>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<OsStr> {
+    let args = args.into_iter().map(|arg| arg.as_ref().to_owned()).collect::<Vec<_>>();
+    let command_line = std::iter::once(command.to_owned())
+        .chain(args.iter().map(|arg| arg.to_string_lossy().into_owned()))
+        .collect::<Vec<_>>()
+        .join(" ");
+
     let mut command = std::process::Command::new(command);
     command
         .env("LANG", "en")
         .stdin(Stdio::inherit())
-        .args(args);
+        .args(&args);
 
     match command.output() {
         Ok(result) => {
             if result.status.success() {
-                Ok(String::from_utf8(result.stdout).unwrap())
+                Ok(String::from_utf8_lossy(&result.stdout).into_owned())
             } else {
-                Err(String::from_utf8(result.stderr).unwrap())
+                Err(CommandError {
+                    command: command_line,
+                    stderr: String::from_utf8_lossy(&result.stderr).into_owned(),
+                    exit_code: result.status.code()
+                })
             }
         }
-        Err(_) => Err(String::new())
+        Err(err) => Err(CommandError {
+            command: command_line,
+            stderr: format!("could not execute: {}", err),
+            exit_code: None
+        })
     }
 }
 
@@ -35,4 +72,81 @@ pub fn temp_filename(suffix: &str) -> PathBuf {
         .collect();
 
     std::env::temp_dir().join(Path::new(&format!("{}{}", rand_name, suffix)))
-}
\ No newline at end of file
+}
+
+/// Names of VMs and volumes are used as file names, so only letters, digits, '-', '_' and '.' are allowed, and they
+/// can't start with '.'.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+pub fn base64_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut decoded = Vec::with_capacity(text.len() / 4 * 3);
+    let mut group = 0u32;
+    let mut num_bits = 0;
+    for character in text.bytes().filter(|character| !character.is_ascii_whitespace() && *character != b'=') {
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|&alphabet_character| alphabet_character == character)
+            .ok_or_else(|| format!("Invalid base64 character '{}'", character as char))?;
+
+        group = group << 6 | value as u32;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            decoded.push((group >> num_bits) as u8);
+            group &= (1 << num_bits) - 1;
+        }
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_round_trip() {
+        for (data, encoded) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foobar", "Zm9vYmFy")] {
+            assert_eq!(encoded, base64_encode(data.as_bytes()));
+            assert_eq!(data.as_bytes(), base64_decode(encoded).unwrap().as_slice());
+        }
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("web-1.test_vm"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("../x"));
+        assert!(!is_valid_name(".hidden"));
+        assert!(!is_valid_name("a/b"));
+    }
+
+    #[test]
+    fn test_base64_decode_invalid() {
+        assert!(base64_decode("Zm9v!").is_err());
+    }
+}
@@ -0,0 +1,441 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use regex::Regex;
+use virt::connect::Connect;
+use virt::domain::{Domain, DomainState};
+
+use crate::definition::{MacAddress, Network, NetworkInterface};
+use crate::error::Error;
+use crate::helpers::{run_command, CommandError};
+
+/// Indicates if the connection URI is to the libvirt of this host, i.e. it has no host name.
+pub fn is_local_uri(uri: &str) -> bool {
+    match uri.split_once("://") {
+        Some((_, rest)) => rest.is_empty() || rest.starts_with('/'),
+        None => true
+    }
+}
+
+/// The directory where libvirt keeps the UEFI variable stores for the given connection.
+pub fn nvram_directory(uri: &str) -> PathBuf {
+    if uri.contains("/session") {
+        let home = std::env::var("HOME").unwrap_or_else(|_| "/root".to_owned());
+        Path::new(&home).join(".config/libvirt/qemu/nvram")
+    } else {
+        PathBuf::from("/var/lib/libvirt/qemu/nvram")
+    }
+}
+
+/// Connects to the hypervisor. The connection messages go to stderr, so that the output of commands such as inspect
+/// can be piped to other programs.
+pub fn connect(uri: &str) -> Result<Connect, virt::error::Error> {
+    eprintln!("Attempting to connect to hypervisor: '{}'", uri);
+
+    let conn = Connect::open(uri)?;
+    match conn.get_uri() {
+        Ok(u) => eprintln!("Connected to hypervisor at '{}'", u),
+        Err(e) => {
+            disconnect(conn)?;
+            return Err(e);
+        }
+    };
+
+    Ok(conn)
+}
+
+pub fn disconnect(mut conn: Connect) -> Result<(), virt::error::Error> {
+    conn.close()?;
+    eprintln!("Disconnected from hypervisor");
+    Ok(())
+}
+
+/// Runs the given function with a connection to the hypervisor, making sure the connection is closed afterwards.
+pub fn with_connection<T, E, F>(uri: &str, function: F) -> Result<T, Error>
+    where
+        E: Into<Error>,
+        F: FnOnce(&Connect) -> Result<T, E> {
+    let conn = connect(uri)?;
+    let result = function(&conn);
+    disconnect(conn)?;
+    result.map_err(|err| err.into())
+}
+
+pub fn create_and_start_vm(conn: &Connect, vm_definition: &str) -> Result<(), virt::error::Error> {
+    let domain = Domain::define_xml(conn, vm_definition)?;
+    if let Err(err) = domain.create() {
+        // Don't leave a VM behind that can't be started
+        let _ = domain.undefine();
+        return Err(err);
+    }
+
+    println!("Created VM.");
+    Ok(())
+}
+
+pub fn remove_vm(conn: &Connect, name: &str, force: bool) -> Result<(), LifecycleError> {
+    let domain = match Domain::lookup_by_name(conn, name) {
+        Ok(domain) => domain,
+        Err(_) => {
+            println!("VM {} is not defined in the hypervisor.", name);
+            return Ok(());
+        }
+    };
+
+    let (state, _) = domain.get_state().map_err(LifecycleError::Libvirt)?;
+    if !is_stopped(state) {
+        if !force {
+            return Err(LifecycleError::InvalidState { name: name.to_owned(), state: state_name(state) });
+        }
+
+        domain.destroy().map_err(LifecycleError::Libvirt)?;
+    }
+
+    domain.undefine().map_err(LifecycleError::Libvirt)
+}
+
+pub fn state_name(state: DomainState) -> &'static str {
+    match state {
+        virt::domain::VIR_DOMAIN_RUNNING => "running",
+        virt::domain::VIR_DOMAIN_BLOCKED => "blocked",
+        virt::domain::VIR_DOMAIN_PAUSED => "paused",
+        virt::domain::VIR_DOMAIN_SHUTDOWN => "shutting down",
+        virt::domain::VIR_DOMAIN_SHUTOFF => "stopped",
+        virt::domain::VIR_DOMAIN_CRASHED => "crashed",
+        virt::domain::VIR_DOMAIN_PMSUSPENDED => "suspended",
+        _ => "unknown"
+    }
+}
+
+pub fn vm_state(conn: &Connect, name: &str) -> &'static str {
+    match Domain::lookup_by_name(conn, name).and_then(|domain| domain.get_state()) {
+        Ok((state, _)) => state_name(state),
+        Err(_) => "not defined"
+    }
+}
+
+/// The MAC addresses of the network interfaces of all VMs defined in the hypervisor.
+pub fn defined_mac_addresses(conn: &Connect) -> Result<Vec<MacAddress>, virt::error::Error> {
+    let mut mac_addresses = Vec::new();
+    for domain in conn.list_all_domains(0)? {
+        mac_addresses.extend(parse_mac_addresses(&domain.get_xml_desc(0)?));
+    }
+
+    Ok(mac_addresses)
+}
+
+fn parse_mac_addresses(domain_xml: &str) -> Vec<MacAddress> {
+    let mac_regex = Regex::new(r#"<mac address=['"]([0-9a-fA-F:]+)['"]"#).unwrap();
+    mac_regex
+        .captures_iter(domain_xml)
+        .filter_map(|captures| captures[1].parse().ok())
+        .collect()
+}
+
+/// An IP address assigned to a network interface of a VM.
+#[derive(Debug, Clone)]
+pub struct InterfaceAddress {
+    pub mac_address: String,
+    pub address: String
+}
+
+/// Where libvirt gets the addresses of the VM from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSource {
+    /// The DHCP leases of the libvirt networks.
+    Lease,
+    /// The QEMU guest agent in the VM.
+    Agent,
+    /// The ARP table of the host.
+    Arp
+}
+
+impl AddressSource {
+    pub const ALL: [AddressSource; 3] = [AddressSource::Lease, AddressSource::Agent, AddressSource::Arp];
+
+    fn name(&self) -> &'static str {
+        match self {
+            AddressSource::Lease => "lease",
+            AddressSource::Agent => "agent",
+            AddressSource::Arp => "arp"
+        }
+    }
+}
+
+/// Returns the IPv4 addresses of the VM from the given source.
+pub fn interface_addresses(uri: &str, name: &str, source: AddressSource) -> Result<Vec<InterfaceAddress>, CommandError> {
+    let output = run_command("virsh", ["-c", uri, "domifaddr", name, "--source", source.name()])?;
+    Ok(parse_interface_addresses(&output))
+}
+
+fn parse_interface_addresses(output: &str) -> Vec<InterfaceAddress> {
+    // Skip the header and the separator line. Further addresses of an interface are listed with - as name and MAC address.
+    let mut addresses = Vec::new();
+    let mut mac_address = "";
+    for line in output.lines().skip(2) {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        if parts.len() != 4 {
+            continue;
+        }
+
+        if parts[1] != "-" {
+            mac_address = parts[1];
+        }
+
+        if parts[2] == "ipv4" {
+            addresses.push(InterfaceAddress {
+                mac_address: mac_address.to_owned(),
+                address: parts[3].split('/').next().unwrap().to_owned()
+            });
+        }
+    }
+
+    addresses
+}
+
+/// Waits for the network interface with the given MAC address to get an IPv4 address from a libvirt network.
+pub fn wait_for_interface_address(uri: &str, name: &str, mac_address: &str, timeout: Duration) -> Result<Option<String>, CommandError> {
+    let start_time = Instant::now();
+    loop {
+        let address = interface_addresses(uri, name, AddressSource::Lease)?
+            .into_iter()
+            .find(|address| address.mac_address.eq_ignore_ascii_case(mac_address));
+
+        if let Some(address) = address {
+            return Ok(Some(address.address));
+        }
+
+        if start_time.elapsed() >= timeout {
+            return Ok(None);
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+/// Returns the IPv4 addresses of the network interfaces of a running VM, in the order of the interfaces. The address
+/// of each interface is taken from the first source that knows it, as bridged interfaces are not leased by libvirt and
+/// the guest agent might not be installed.
+pub fn vm_addresses(uri: &str, name: &str, network_interfaces: &[NetworkInterface]) -> Vec<InterfaceAddress> {
+    let mut source_addresses = Vec::new();
+    let mut addresses = Vec::new();
+    for network_interface in network_interfaces {
+        let mac_address = network_interface.mac_address.to_string();
+        for (index, source) in AddressSource::ALL.iter().enumerate() {
+            // Only ask each source once, and only if needed
+            if source_addresses.len() <= index {
+                source_addresses.push(interface_addresses(uri, name, *source).unwrap_or_default());
+            }
+
+            let interface_addresses = source_addresses[index]
+                .iter()
+                .filter(|address| address.mac_address.eq_ignore_ascii_case(&mac_address))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if !interface_addresses.is_empty() {
+                addresses.extend(interface_addresses);
+                break;
+            }
+        }
+    }
+
+    addresses
+}
+
+/// Returns the address that the host can reach the VM at. Interfaces using user-mode networking are not reachable.
+pub fn guest_address(uri: &str, name: &str, network_interfaces: &[NetworkInterface]) -> Option<String> {
+    let reachable_interfaces = network_interfaces
+        .iter()
+        .filter(|network_interface| network_interface.network != Network::User)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if reachable_interfaces.is_empty() {
+        return None;
+    }
+
+    vm_addresses(uri, name, &reachable_interfaces).into_iter().next().map(|address| address.address)
+}
+
+/// Waits for the VM to get an address that the host can reach it at.
+pub fn wait_for_guest_address(uri: &str, name: &str, network_interfaces: &[NetworkInterface], timeout: Duration) -> Option<String> {
+    let start_time = Instant::now();
+    loop {
+        if let Some(address) = guest_address(uri, name, network_interfaces) {
+            return Some(address);
+        }
+
+        if start_time.elapsed() >= timeout {
+            return None;
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[derive(Debug)]
+pub enum LifecycleError {
+    NotFound(String),
+    InvalidState { name: String, state: &'static str },
+    Libvirt(virt::error::Error)
+}
+
+impl LifecycleError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LifecycleError::Libvirt(_) => 1,
+            LifecycleError::NotFound(_) => 2,
+            LifecycleError::InvalidState { .. } => 3
+        }
+    }
+}
+
+impl std::fmt::Display for LifecycleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LifecycleError::NotFound(name) => write!(f, "No VM named {} is defined in the hypervisor", name),
+            LifecycleError::InvalidState { name, state } => write!(f, "VM {} is {}", name, state),
+            LifecycleError::Libvirt(err) => write!(f, "libvirt error: code {}, message: {}", err.code, err.message)
+        }
+    }
+}
+
+pub enum StopResult {
+    AlreadyStopped,
+    ShutDown,
+    Destroyed
+}
+
+fn lookup_domain(conn: &Connect, name: &str) -> Result<(Domain, DomainState), LifecycleError> {
+    let domain = Domain::lookup_by_name(conn, name).map_err(|_| LifecycleError::NotFound(name.to_owned()))?;
+    let (state, _) = domain.get_state().map_err(LifecycleError::Libvirt)?;
+    Ok((domain, state))
+}
+
+fn is_stopped(state: DomainState) -> bool {
+    state == virt::domain::VIR_DOMAIN_SHUTOFF || state == virt::domain::VIR_DOMAIN_CRASHED
+}
+
+/// Indicates if the VM is running (or paused), as opposed to stopped.
+pub fn is_running(conn: &Connect, name: &str) -> Result<bool, LifecycleError> {
+    let (_, state) = lookup_domain(conn, name)?;
+    Ok(!is_stopped(state))
+}
+
+/// Starts a defined VM. Starting an already running VM does nothing.
+pub fn start_vm(conn: &Connect, name: &str) -> Result<bool, LifecycleError> {
+    let (domain, state) = lookup_domain(conn, name)?;
+    if !is_stopped(state) {
+        return Ok(false);
+    }
+
+    domain.create().map_err(LifecycleError::Libvirt)?;
+    Ok(true)
+}
+
+/// Sends an ACPI shutdown to the VM and waits for it to stop. If it has not stopped within the timeout, it is destroyed.
+pub fn stop_vm(conn: &Connect, name: &str, timeout: Duration) -> Result<StopResult, LifecycleError> {
+    let (domain, state) = lookup_domain(conn, name)?;
+    if is_stopped(state) {
+        return Ok(StopResult::AlreadyStopped);
+    }
+
+    // A paused guest can't react to the shutdown, so it is resumed first
+    if state == virt::domain::VIR_DOMAIN_PAUSED {
+        domain.resume().map_err(LifecycleError::Libvirt)?;
+    }
+
+    domain.shutdown().map_err(LifecycleError::Libvirt)?;
+
+    let start_time = Instant::now();
+    while start_time.elapsed() < timeout {
+        let (state, _) = domain.get_state().map_err(LifecycleError::Libvirt)?;
+        if is_stopped(state) {
+            return Ok(StopResult::ShutDown);
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
+    }
+
+    domain.destroy().map_err(LifecycleError::Libvirt)?;
+    Ok(StopResult::Destroyed)
+}
+
+pub fn restart_vm(conn: &Connect, name: &str, timeout: Duration) -> Result<(), LifecycleError> {
+    stop_vm(conn, name, timeout)?;
+    start_vm(conn, name)?;
+    Ok(())
+}
+
+pub fn kill_vm(conn: &Connect, name: &str) -> Result<(), LifecycleError> {
+    let (domain, state) = lookup_domain(conn, name)?;
+    if is_stopped(state) {
+        return Err(LifecycleError::InvalidState { name: name.to_owned(), state: state_name(state) });
+    }
+
+    domain.destroy().map_err(LifecycleError::Libvirt)
+}
+
+pub fn pause_vm(conn: &Connect, name: &str) -> Result<(), LifecycleError> {
+    let (domain, state) = lookup_domain(conn, name)?;
+    if state != virt::domain::VIR_DOMAIN_RUNNING {
+        return Err(LifecycleError::InvalidState { name: name.to_owned(), state: state_name(state) });
+    }
+
+    domain.suspend().map_err(LifecycleError::Libvirt)?;
+    Ok(())
+}
+
+pub fn unpause_vm(conn: &Connect, name: &str) -> Result<(), LifecycleError> {
+    let (domain, state) = lookup_domain(conn, name)?;
+    if state != virt::domain::VIR_DOMAIN_PAUSED {
+        return Err(LifecycleError::InvalidState { name: name.to_owned(), state: state_name(state) });
+    }
+
+    domain.resume().map_err(LifecycleError::Libvirt)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interface_addresses() {
+        let output = concat!(
+            " Name       MAC address          Protocol     Address\n",
+            "-------------------------------------------------------------------------------\n",
+            " lo         00:00:00:00:00:00    ipv4         127.0.0.1/8\n",
+            " -          -                    ipv6         ::1/128\n",
+            " enp1s0     52:54:00:a1:b2:c3    ipv6         fe80::5054:ff:fea1:b2c3/64\n",
+            " -          -                    ipv4         192.168.122.57/24\n"
+        );
+
+        let addresses = parse_interface_addresses(output);
+        assert_eq!(2, addresses.len());
+        assert_eq!("00:00:00:00:00:00", addresses[0].mac_address);
+        assert_eq!("52:54:00:a1:b2:c3", addresses[1].mac_address);
+        assert_eq!("192.168.122.57", addresses[1].address);
+    }
+
+    #[test]
+    fn test_is_local_uri() {
+        assert!(is_local_uri("qemu:///system"));
+        assert!(is_local_uri("qemu+unix:///session"));
+        assert!(is_local_uri("test:///default"));
+        assert!(!is_local_uri("qemu+ssh://user@host/system"));
+        assert!(!is_local_uri("qemu+tcp://10.0.0.2/system"));
+    }
+
+    #[test]
+    fn test_parse_mac_addresses() {
+        let domain_xml = concat!(
+            "<interface type='network'>\n  <mac address='52:54:00:a1:b2:c3'/>\n</interface>\n",
+            "<interface type=\"bridge\">\n  <mac address=\"52:54:00:d4:e5:f6\"/>\n</interface>\n"
+        );
+
+        let expected: Vec<MacAddress> = vec!["52:54:00:a1:b2:c3".parse().unwrap(), "52:54:00:d4:e5:f6".parse().unwrap()];
+        assert_eq!(expected, parse_mac_addresses(domain_xml));
+    }
+}
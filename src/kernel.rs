@@ -1,6 +1,8 @@
 use std::path::Path;
 use regex::Regex;
 
+use crate::helpers::{run_command, CommandError};
+
 #[derive(Debug)]
 pub struct LinuxKernel {
     pub kernel: String,
@@ -16,13 +18,14 @@ impl LinuxKernel {
 
         let active_kernel = boot_path.join("vmlinuz").canonicalize().ok();
 
+        let kernel_regex = Regex::new("vmlinuz-(.*)").unwrap();
         let mut kernels = Vec::new();
         for entry in dir_entries {
             let entry = entry?;
             let path = entry.path();
             if path.is_file() {
                 let filename = path.file_name().unwrap().to_str().unwrap().to_owned();
-                if let Some(kernel_match) = Regex::new("vmlinuz-(.*)").unwrap().captures(&filename) {
+                if let Some(kernel_match) = kernel_regex.captures(&filename) {
                     let version = kernel_match.get(1).unwrap().as_str();
                     let initrd_path = boot_path.join(format!("initrd.img-{}", version));
 
@@ -41,4 +44,23 @@ impl LinuxKernel {
         kernels.sort_by_key(|kernel| kernel.version.clone());
         Ok(kernels)
     }
-}
\ No newline at end of file
+
+    /// Returns the files of the given kernel modules and the modules they depend on, in the order to load them.
+    /// Modules built into the kernel have no files.
+    pub fn module_files(&self, modules: &[&str]) -> Result<Vec<String>, CommandError> {
+        let mut files = Vec::new();
+        for module in modules {
+            let output = run_command("modprobe", ["--show-depends", "--set-version", &self.version, module])?;
+            for line in output.lines() {
+                if let Some(file) = line.strip_prefix("insmod ") {
+                    let file = file.split_whitespace().next().unwrap_or("").to_owned();
+                    if !file.is_empty() && !files.contains(&file) {
+                        files.push(file);
+                    }
+                }
+            }
+        }
+
+        Ok(files)
+    }
+}
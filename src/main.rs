@@ -1,37 +1,150 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use structopt::StructOpt;
-
-use virt::connect::Connect;
-use virt::domain::Domain;
+use structopt::clap::AppSettings;
 
 mod helpers;
 mod definition;
+mod domain_xml;
 mod disk_creator;
 mod docker_image;
 mod kernel;
+mod hypervisor;
+mod state;
+mod port_forward;
+mod console;
+mod guest_agent;
+mod file_copy;
+mod entrypoint;
+mod overlay;
+mod cloud_init;
+mod ssh;
+mod health;
+mod mounts;
+mod volume;
+mod error;
 
-use crate::definition::{VirtualMachine, Disk, FileSystem};
+use crate::definition::{
+    VirtualMachine, Disk, FileSystem, DomainType, MacAddress, Network, NetworkInterface, KernelCommandLine,
+    SharedFolder, SharedFolderDriver
+};
 use crate::kernel::LinuxKernel;
+use crate::state::{StateStore, StateStoreError, VirtualMachineState};
+use crate::hypervisor::{LifecycleError, StopResult};
+use crate::console::AttachResult;
+use crate::file_copy::CopyPath;
+use crate::entrypoint::{Entrypoint, EntrypointOverrides, InitConfig};
+use crate::overlay::{OverlaySetup, AuthorizedKeys, KernelModules};
+use crate::mounts::{Mount, MountArgument, FstabEntry};
+use crate::volume::{VolumeStore, VolumeError};
+use crate::disk_creator::{DiskFormat, DiskInfo};
+use crate::cloud_init::CloudInitSeed;
+use crate::port_forward::{PortMapping, PublishedPort};
+use crate::health::{Health, HealthCheck, ReadinessCheck};
+use crate::error::Error;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name="docker-on-kvm", about="Run docker images as KVM VMs")]
-enum CommandLineInput {
+struct CommandLineInput {
+    #[structopt(long, global=true, env="DOCKER_ON_KVM_URI", default_value="qemu:///system", help="The libvirt connection URI, e.g. qemu:///session or qemu+ssh://host/system")]
+    connect: String,
+    #[structopt(subcommand)]
+    command: Command
+}
+
+#[derive(Debug, StructOpt)]
+struct RunArguments {
+    #[structopt(name="docker_image", help="The tag of the docker image to run")]
+    docker_image: String,
+    #[structopt(name="name", help="The name of the VM")]
+    name: String,
+    #[structopt(long, help="The size of the disk in megabytes. Defaults to the size of the image plus the headroom.")]
+    disk_size: Option<u64>,
+    #[structopt(long, help="The free space to leave on the disk when sized automatically, in percent of the image size", default_value="25")]
+    disk_headroom: u64,
+    #[structopt(long, help="The file system of the root disk: ext4, xfs, btrfs, or squashfs or erofs for a read-only base with a writable layer", default_value="ext4")]
+    filesystem: FileSystem,
+    #[structopt(long, help="The amount of RAM in megabytes.", default_value="2048")]
+    ram_size: u64,
+    #[structopt(long, help="The number of CPU cores", default_value="2")]
+    num_cpus: u64,
+    #[structopt(long, help="The linux kernel on the current system to use. Use list-kernels command to get the available ones.")]
+    kernel: Option<String>,
+    #[structopt(long, help="The QEMU machine type", default_value="q35")]
+    machine: String,
+    #[structopt(long, help="The UEFI firmware to boot with", default_value="/usr/share/OVMF/OVMF_CODE.ms.fd")]
+    uefi_loader: String,
+    #[structopt(long, help="Boot using BIOS instead of UEFI")]
+    bios: bool,
+    #[structopt(long, help="The MAC address of the first network interface. Derived from the VM UUID if not given.")]
+    mac: Option<MacAddress>,
+    #[structopt(long="network", help="Attaches a network interface to the given libvirt network. Can be given multiple times.")]
+    networks: Vec<String>,
+    #[structopt(long="bridge", help="Attaches a network interface to the given host bridge. Can be given multiple times.")]
+    bridges: Vec<String>,
+    #[structopt(long, help="Attaches a network interface using user-mode networking, which works in session mode")]
+    user_network: bool,
+    #[structopt(long, help="Creates the VM without any network interface", conflicts_with_all=&["networks", "bridges", "user-network", "mac"])]
+    no_network: bool,
+    #[structopt(short, long, help="Publishes a port of the VM on the host as [ip:]host:guest[/tcp|udp]. Can be given multiple times.")]
+    publish: Vec<PortMapping>,
+    #[structopt(long, help="Runs the VM without graphics, sound and USB redirection, using the serial console as the primary console")]
+    headless: bool,
+    #[structopt(long="kernel-arg", help="Appends an argument to the kernel command line. Can be given multiple times.")]
+    kernel_args: Vec<String>,
+    #[structopt(long, help="Replaces the whole kernel command line, including the root disk arguments", conflicts_with_all=&["kernel-args", "target"])]
+    kernel_cmdline: Option<String>,
+    #[structopt(long, help="The systemd unit to boot into. Defaults to graphical.target, or multi-user.target when headless.")]
+    target: Option<String>,
+    #[structopt(long, help="Adds the serial port as a kernel console")]
+    serial_console: bool,
+    #[structopt(short, long, help="Attaches to the serial console after the VM has started. Implies --serial-console.")]
+    attach: bool,
+    #[structopt(long, help="Installs and enables qemu-guest-agent in the image if it lacks it, which is needed by exec")]
+    guest_agent: bool,
+    #[structopt(long, help="Boots with a minimal init that runs the image command instead of systemd, for images without an init system", conflicts_with="target")]
+    init: bool,
+    #[structopt(long, help="The statically linked init binary to install with --init. Defaults to the musl build of docker-on-kvm-init, or the one next to this executable.")]
+    init_binary: Option<PathBuf>,
+    #[structopt(short, long="env", number_of_values=1, help="Sets an environment variable as KEY=VALUE, or as KEY to take the value from the current environment. Can be given multiple times.")]
+    env: Vec<String>,
+    #[structopt(long, number_of_values=1, help="Reads environment variables from a file with one KEY=VALUE per line. Can be given multiple times.")]
+    env_file: Vec<PathBuf>,
+    #[structopt(long, help="The hostname of the VM. Defaults to the name of the VM with --init.")]
+    hostname: Option<String>,
+    #[structopt(short, long, help="The user to run the command as, given as user[:group]")]
+    user: Option<String>,
+    #[structopt(short, long, help="The working directory of the command")]
+    workdir: Option<String>,
+    #[structopt(short="v", long="volume", number_of_values=1, help="Mounts a host directory (given as a path) or a volume in the VM as source:guest_path[:ro]. Can be given multiple times.")]
+    mounts: Vec<MountArgument>,
+    #[structopt(long, help="Attaches a cloud-init NoCloud seed with the given user-data file, for images that ship cloud-init")]
+    cloud_init: Option<PathBuf>,
+    #[structopt(long, number_of_values=1, help="Adds an SSH public key, given as a file or as the key itself, to the authorized keys of the VM. Can be given multiple times.")]
+    ssh_key: Vec<String>,
+    #[structopt(long, help="Does not add the public keys in ~/.ssh to the authorized keys of the VM")]
+    no_ssh_keys: bool,
+    #[structopt(long, help="The user in the VM to authorize the SSH keys for. Defaults to the user of the image, or root.")]
+    ssh_user: Option<String>,
+    #[structopt(long, help="Waits until the VM has an IP address that the host can reach it at, and prints it")]
+    wait_ip: bool,
+    #[structopt(long, help="Waits until the VM is ready: until the health check of the image succeeds, or the guest agent responds if it has none")]
+    wait: bool,
+    #[structopt(long, help="Waits until the given TCP port of the VM accepts connections")]
+    wait_port: Option<u16>,
+    #[structopt(long, help="Seconds to wait for the VM with --wait-ip, --wait and --wait-port", default_value="120")]
+    wait_timeout: u64,
+    #[structopt(name="command", last=true, help="Replaces the command of the image, given after --")]
+    command: Vec<String>
+}
+
+// Only constructed once when parsing the command line
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, StructOpt)]
+enum Command {
     #[structopt(about="Runs a docker image as KVM")]
-    Run {
-        #[structopt(name="docker_image", help="The tag of the docker image to run")]
-        docker_image: String,
-        #[structopt(name="name", help="The name of the VM")]
-        name: String,
-        #[structopt(long, help="The size of the disk in megabytes.", default_value="2048")]
-        disk_size: u64,
-        #[structopt(long, help="The amount of RAM in megabytes.", default_value="2048")]
-        ram_size: u64,
-        #[structopt(long, help="The number of CPU cores", default_value="2")]
-        num_cpus: u64,
-        #[structopt(long, help="The linux kernel on the current system to use. Use list-kernels command to get the available ones.")]
-        kernel: Option<String>,
-    },
+    Run(RunArguments),
     #[structopt(about="Simple wrapper around docker build")]
     Build {
         #[structopt(name="filename", help="The docker file to build")]
@@ -40,119 +153,882 @@ enum CommandLineInput {
         tag: String
     },
     #[structopt(about="Lists the linux kernels available")]
-    ListKernels
+    ListKernels,
+    #[structopt(about="Lists the created VMs", alias="list")]
+    Ps,
+    #[structopt(about="Shows the stored information about a VM")]
+    Inspect {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Removes a VM together with its disk overlay and NVRAM")]
+    Rm {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String,
+        #[structopt(long, help="Stops the VM if it is running")]
+        force: bool
+    },
+    #[structopt(about="Starts a stopped VM")]
+    Start {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Shuts down a VM gracefully, destroying it if it does not stop within the timeout")]
+    Stop {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String,
+        #[structopt(short, long, help="Seconds to wait for the shutdown before destroying the VM", default_value="10")]
+        time: u64
+    },
+    #[structopt(about="Stops and starts a VM")]
+    Restart {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String,
+        #[structopt(short, long, help="Seconds to wait for the shutdown before destroying the VM", default_value="10")]
+        time: u64
+    },
+    #[structopt(about="Grows the disk of a stopped VM, whose file system is grown when it boots")]
+    Resize {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String,
+        #[structopt(name="size", help="The new size of the disk in megabytes")]
+        size: u64
+    },
+    #[structopt(about="Forcefully stops a running VM")]
+    Kill {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Pauses a running VM")]
+    Pause {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Resumes a paused VM", alias="resume")]
+    Unpause {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Attaches the terminal to the serial console of a running VM")]
+    Attach {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Runs a command in a running VM through the QEMU guest agent", setting=AppSettings::TrailingVarArg)]
+    Exec {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String,
+        #[structopt(name="command", help="The command to run", required=true)]
+        command: Vec<String>
+    },
+    #[structopt(about="Copies files between a VM and the host, preserving ownership")]
+    Cp {
+        #[structopt(name="source", help="The path to copy from, given as name:/path for a path in a VM")]
+        source: CopyPath,
+        #[structopt(name="destination", help="The path to copy to, given as name:/path for a path in a VM")]
+        destination: CopyPath
+    },
+    #[structopt(about="Shows the IP addresses of a running VM")]
+    Ip {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Manages volumes, data disks that outlive the VMs using them")]
+    Volume(VolumeCommand),
+    #[structopt(about="Checks if a running VM is healthy, using the health check of the image or else the guest agent")]
+    Health {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String
+    },
+    #[structopt(about="Connects to a running VM using ssh", setting=AppSettings::TrailingVarArg)]
+    Ssh {
+        #[structopt(name="name", help="The name of the VM")]
+        name: String,
+        #[structopt(short="l", long, help="The user to log in as. Defaults to the user that the SSH keys were added for.")]
+        user: Option<String>,
+        #[structopt(name="args", help="Arguments to ssh, such as a command to run")]
+        args: Vec<String>
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum VolumeCommand {
+    #[structopt(about="Creates a volume")]
+    Create {
+        #[structopt(name="name", help="The name of the volume")]
+        name: String,
+        #[structopt(long, help="The size of the volume in megabytes", default_value="1024")]
+        size: u64,
+        #[structopt(long, help="The format of the disk image, qcow2 or raw", default_value="qcow2")]
+        format: DiskFormat,
+        #[structopt(long, help="The file system of the volume", default_value="ext4")]
+        filesystem: FileSystem
+    },
+    #[structopt(about="Lists the volumes", alias="list")]
+    Ls,
+    #[structopt(about="Removes a volume that is not used by any VM")]
+    Rm {
+        #[structopt(name="name", help="The name of the volume")]
+        name: String
+    },
+    #[structopt(about="Shows the stored information about a volume")]
+    Inspect {
+        #[structopt(name="name", help="The name of the volume")]
+        name: String
+    }
 }
 
 fn main() {
     let command_line_input = CommandLineInput::from_args();
 
-    match command_line_input {
-        CommandLineInput::Run { docker_image, name, disk_size, ram_size, num_cpus, kernel } => {
-            let kernels = LinuxKernel::find().unwrap();
-            let selected_kernel = match kernel {
-                Some(kernel) => {
-                    kernels
-                        .iter()
-                        .find(|linux_kernel| &linux_kernel.version == &kernel)
-                        .expect("Could not find the specified linux kernel.")
-                },
-                None => kernels.last().unwrap()
-            };
+    if let Err(err) = execute(command_line_input) {
+        eprintln!("Error: {}.", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+fn execute(command_line_input: CommandLineInput) -> Result<(), Error> {
+    let uri = command_line_input.connect;
+    let state_store = StateStore::new(Path::new("vms"));
+    let volume_store = VolumeStore::new(Path::new("volumes"));
+
+    match command_line_input.command {
+        Command::Run(arguments) => {
+            run(&uri, &state_store, &volume_store, arguments)?;
+        }
+        Command::Build { filename, tag } => {
+            docker_image::build(Path::new(&filename), &tag)?;
+        },
+        Command::ListKernels => {
+            println!("Found the following linux kernels:");
+            for kernel in LinuxKernel::find()? {
+                println!("{} (path: {}, active: {})", kernel.version, kernel.kernel, kernel.active)
+            }
+        }
+        Command::Ps => {
+            let vm_states = state_store.list()?;
+            let conn = hypervisor::connect(&uri)?;
 
-            let vm_name = name;
-            let vm_uuid = uuid::Uuid::new_v4().to_simple().to_string();
-            let disk_size_in_megabytes = disk_size;
-            let ram_in_megabytes = ram_size;
+            println!("{:<20} {:<34} {:<20} {:<20} {:<14} {:<20} CREATED", "NAME", "UUID", "IMAGE", "KERNEL", "STATE", "ADDRESSES");
+            for vm_state in vm_states {
+                let addresses = running_vm_addresses(&uri, &conn, &vm_state);
+                println!(
+                    "{:<20} {:<34} {:<20} {:<20} {:<14} {:<20} {}",
+                    vm_state.name,
+                    vm_state.uuid,
+                    vm_state.image,
+                    vm_state.kernel_version,
+                    hypervisor::vm_state(&conn, &vm_state.name),
+                    addresses.join(","),
+                    vm_state.created.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+
+            hypervisor::disconnect(conn)?;
+        }
+        Command::Inspect { name } => {
+            let vm_state = state_store.get(&name)?;
+            let (addresses, exit_code) = hypervisor::with_connection(&uri, |conn| {
+                Ok::<_, Error>((running_vm_addresses(&uri, conn, &vm_state), stopped_vm_exit_code(conn, &vm_state)))
+            })?;
 
-            let extracted_images_dir = Path::new("extracted-images");
-            let disks_dir = Path::new("disks");
+            let mut vm_state = serde_json::to_value(&vm_state).map_err(StateStoreError::Serialization)?;
+            vm_state["addresses"] = serde_json::json!(addresses);
+            vm_state["exit_code"] = serde_json::json!(exit_code);
+            println!("{}", serde_json::to_string_pretty(&vm_state).map_err(StateStoreError::Serialization)?);
+        }
+        Command::Rm { name, force } => {
+            let vm_state = state_store.get(&name)?;
 
-            let docker_image_extraction = docker_image::try_extract_image(&docker_image, extracted_images_dir).unwrap();
-            let destination_disk = disks_dir.join(format!("{}.img", docker_image_extraction.file_name().unwrap().to_str().unwrap()));
+            hypervisor::with_connection(&uri, |conn| hypervisor::remove_vm(conn, &name, force))?;
 
-            if !destination_disk.exists() {
-                disk_creator::create_from_directory(
-                    &destination_disk,
-                    disk_size_in_megabytes * 1024 * 1024,
-                    FileSystem::Ext4,
-                    &docker_image_extraction
-                ).unwrap();
+            // The rules might already be gone, e.g. after a host reboot, which should not prevent the removal
+            if let Err(err) = port_forward::unpublish_ports(&vm_state.published_ports) {
+                eprintln!("Failed to remove the port forwarding of VM {}: {}.", name, err);
             }
 
-            println!("Creating VM {} ({}) using docker image {} and kernel {}", vm_name, vm_uuid, docker_image, selected_kernel.version);
+            remove_vm_files(&vm_state)?;
+            state_store.remove(&name)?;
+            println!("Removed VM {}.", name);
+        }
+        Command::Start { name } => {
+            let started = hypervisor::with_connection(&uri, |conn| hypervisor::start_vm(conn, &name))?;
 
-            let cow_disk = disks_dir.join(format!("{}.qcow2", vm_uuid));
-            disk_creator::create_copy_on_write_image(
-                &cow_disk,
-                &destination_disk,
-            ).unwrap();
-            let destination_disk = cow_disk;
-
-            let vm_definition = VirtualMachine {
-                name: vm_name,
-                uuid: Some(vm_uuid),
-                kernel_file: selected_kernel.kernel.clone(),
-                initrd_file: selected_kernel.initrd.clone(),
-                root_disk: Disk::File {
-                    filename: destination_disk.canonicalize().unwrap().to_str().unwrap().to_owned(),
-                    filesystem: FileSystem::Ext4
-                },
-                ram_in_bytes: ram_in_megabytes * 1024 * 1024,
-                num_cpus
-            }.get_xml().unwrap();
-
-            create_and_start_vm(&vm_definition);
-        }
-        CommandLineInput::Build { filename, tag } => {
-            docker_image::build(Path::new(&filename), &tag).unwrap();
-        },
-        CommandLineInput::ListKernels => {
-            println!("Found the following linux kernels:");
-            for kernel in LinuxKernel::find().unwrap() {
-                println!("{} (path: {}, active: {})", kernel.version, kernel.kernel, kernel.active)
+            if started {
+                println!("Started VM {}.", name);
+                publish_ports(&uri, &state_store, &mut state_store.get(&name)?)?;
+            } else {
+                println!("VM {} is already running.", name);
+            }
+        }
+        Command::Stop { name, time } => {
+            let result = hypervisor::with_connection(&uri, |conn| hypervisor::stop_vm(conn, &name, Duration::from_secs(time)))?;
+
+            match result {
+                StopResult::AlreadyStopped => println!("VM {} is already stopped.", name),
+                StopResult::ShutDown => println!("Stopped VM {}.", name),
+                StopResult::Destroyed => println!("VM {} did not shut down within {} seconds and was destroyed.", name, time)
+            }
+
+            unpublish_ports(&state_store, &mut state_store.get(&name)?)?;
+        }
+        Command::Restart { name, time } => {
+            hypervisor::with_connection(&uri, |conn| hypervisor::restart_vm(conn, &name, Duration::from_secs(time)))?;
+            println!("Restarted VM {}.", name);
+            publish_ports(&uri, &state_store, &mut state_store.get(&name)?)?;
+        }
+        Command::Kill { name } => {
+            hypervisor::with_connection(&uri, |conn| hypervisor::kill_vm(conn, &name))?;
+            println!("Killed VM {}.", name);
+            unpublish_ports(&state_store, &mut state_store.get(&name)?)?;
+        }
+        Command::Resize { name, size } => {
+            let vm_state = state_store.get(&name)?;
+            if hypervisor::with_connection(&uri, |conn| hypervisor::is_running(conn, &name))? {
+                return Err(LifecycleError::InvalidState { name, state: "running" }.into());
+            }
+
+            // The overlay disk of a read-only root is the ext4 writable layer
+            let filesystem = if vm_state.filesystem.is_read_only() { FileSystem::Ext4 } else { vm_state.filesystem };
+            disk_creator::grow_image(&vm_state.overlay_disk, size * 1024 * 1024, &filesystem)?;
+            println!("Resized the disk of VM {} to {} MB.", name, size);
+        }
+        Command::Pause { name } => {
+            hypervisor::with_connection(&uri, |conn| hypervisor::pause_vm(conn, &name))?;
+            println!("Paused VM {}.", name);
+        }
+        Command::Unpause { name } => {
+            hypervisor::with_connection(&uri, |conn| hypervisor::unpause_vm(conn, &name))?;
+            println!("Resumed VM {}.", name);
+        }
+        Command::Attach { name } => {
+            attach(&uri, &name)?;
+        }
+        Command::Exec { name, command } => {
+            let exit_code = guest_agent::exec(&uri, &name, &command)?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        Command::Cp { source, destination } => {
+            file_copy::copy(&uri, &state_store, &source, &destination)?;
+        }
+        Command::Ip { name } => {
+            let vm_state = state_store.get(&name)?;
+            let addresses = hypervisor::vm_addresses(&uri, &name, &vm_state.network_interfaces);
+            if addresses.is_empty() {
+                return Err(Error::NoGuestAddress(name));
+            }
+
+            for address in addresses {
+                println!("{}", address.address);
             }
         }
+        Command::Volume(volume_command) => {
+            volume(&state_store, &volume_store, volume_command)?;
+        }
+        Command::Health { name } => {
+            let vm_state = state_store.get(&name)?;
+            let readiness_check = if vm_state.healthcheck.is_some() { ReadinessCheck::HealthCheck } else { ReadinessCheck::Agent };
+
+            match health::check(&uri, &vm_state, readiness_check) {
+                Health::Healthy => println!("healthy"),
+                Health::Unhealthy(reason) => {
+                    println!("unhealthy: {}", reason);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Ssh { name, user, args } => {
+            let vm_state = state_store.get(&name)?;
+            let address = hypervisor::guest_address(&uri, &name, &vm_state.network_interfaces)
+                .ok_or(Error::NoGuestAddress(name))?;
+
+            let user = user.or(vm_state.ssh_user).unwrap_or_else(|| "root".to_owned());
+            return Err(ssh::connect(&address, &user, &args).into());
+        }
     }
+
+    Ok(())
 }
 
-fn create_and_start_vm(vm_definition: &str) {
-    let uri = "qemu:///system";
-    println!("Attempting to connect to hypervisor: '{}'", uri);
+fn run(uri: &str, state_store: &StateStore, volume_store: &VolumeStore, arguments: RunArguments) -> Result<(), Error> {
+    let networks = select_networks(uri, &arguments);
+
+    // Read-only root file systems are booted with the init, which mounts the writable layer
+    let init_binary = if arguments.init || arguments.filesystem.is_read_only() {
+        Some(entrypoint::find_init_binary(arguments.init_binary.as_deref())?)
+    } else {
+        None
+    };
+
+    let mut environment = Vec::new();
+    for env_file in &arguments.env_file {
+        environment.extend(entrypoint::read_env_file(env_file)?);
+    }
+    environment.extend(arguments.env.iter().filter_map(|variable| entrypoint::resolve_variable(variable)));
+
+    let entrypoint_overrides = EntrypointOverrides {
+        environment,
+        user: arguments.user.clone(),
+        working_directory: arguments.workdir.clone(),
+        command: arguments.command.clone()
+    };
+
+    let mut ssh_keys = arguments.ssh_key.iter().map(|ssh_key| ssh::read_ssh_key(ssh_key)).collect::<Result<Vec<_>, _>>()?;
+    if !arguments.no_ssh_keys {
+        ssh_keys.extend(ssh::default_ssh_keys()?);
+    }
 
-    let conn = match Connect::open(&uri) {
-        Ok(c) => c,
-        Err(e) => panic!(
-            "No connection to hypervisor: code {}, message: {}",
-            e.code, e.message
-        ),
+    let kernels = LinuxKernel::find()?;
+    let selected_kernel = match &arguments.kernel {
+        Some(kernel) => {
+            kernels
+                .iter()
+                .find(|linux_kernel| &linux_kernel.version == kernel)
+                .ok_or_else(|| Error::KernelNotFound(kernel.clone()))?
+        },
+        None => kernels.last().ok_or(Error::NoKernelsFound)?
     };
 
-    match conn.get_uri() {
-        Ok(u) => println!("Connected to hypervisor at '{}'", u),
-        Err(e) => {
-            disconnect(conn);
-            panic!(
-                "Failed to get URI for hypervisor connection: code {}, message: {}",
-                e.code, e.message
-            );
+    if !helpers::is_valid_name(&arguments.name) {
+        return Err(StateStoreError::InvalidName(arguments.name).into());
+    }
+
+    if state_store.exists(&arguments.name) {
+        return Err(StateStoreError::AlreadyExists(arguments.name).into());
+    }
+
+    // Like docker, missing host directories are created
+    let mut mounts = Vec::new();
+    let mut volume_mounts = Vec::new();
+    let mut volumes = Vec::new();
+    for mount in &arguments.mounts {
+        match mount {
+            MountArgument::Bind(mount) => {
+                std::fs::create_dir_all(&mount.host_path)?;
+                mounts.push(Mount { host_path: mount.host_path.canonicalize()?, ..mount.clone() });
+            }
+            MountArgument::Volume(volume_mount) => {
+                let volume = volume_store.get(&volume_mount.name)?;
+
+                // A disk can't be used by two VMs at once
+                if let Some(vm_name) = volume_user(state_store, &volume.name)? {
+                    return Err(VolumeError::InUse { name: volume.name, vm_name }.into());
+                }
+
+                volume_mounts.push(volume_mount.clone());
+                volumes.push(volume);
+            }
         }
+    }
+
+    let vm_name = arguments.name.clone();
+    let vm_uuid = uuid::Uuid::new_v4();
+    let network_interfaces = network_interfaces(uri, state_store, networks, &vm_uuid, arguments.mac)?;
+
+    if !arguments.publish.is_empty() {
+        port_forward::check_publish(uri, &network_interfaces)?;
+    }
+
+    let vm_uuid = vm_uuid.to_simple().to_string();
+    let ram_in_megabytes = arguments.ram_size;
+
+    let extracted_images_dir = Path::new("extracted-images");
+    let disks_dir = Path::new("disks");
+
+    let docker_image = if arguments.guest_agent {
+        docker_image::add_guest_agent(&arguments.docker_image)?
+    } else {
+        arguments.docker_image.clone()
     };
 
-    let domain = Domain::define_xml(&conn, &vm_definition).unwrap();
-    let status = domain.create().unwrap();
-    if status == 0 {
-        println!("Created VM.");
+    let docker_image_extraction = docker_image::try_extract_image(&docker_image, extracted_images_dir)?;
+    let image_id = docker_image_extraction.file_name().unwrap().to_str().unwrap().to_owned();
+    let filesystem = arguments.filesystem;
+    let destination_disk = match filesystem {
+        FileSystem::Ext4 => disks_dir.join(format!("{}.img", image_id)),
+        filesystem => disks_dir.join(format!("{}-{}.img", image_id, filesystem))
+    };
+
+    // Fail before creating anything if the image doesn't fit
+    let requested_disk_size = arguments.disk_size.map(|size| size * 1024 * 1024);
+    let disk_size_in_bytes = if filesystem.is_read_only() {
+        // A read-only base is as large as its content, so the size is of the writable layer, which only holds the changes
+        match requested_disk_size {
+            Some(requested_disk_size) => requested_disk_size,
+            None => disk_creator::disk_size_for_directory(&docker_image_extraction, None, FileSystem::Ext4, arguments.disk_headroom)?
+        }
     } else {
-        println!("Failed to create VM: {}", status);
+        disk_creator::disk_size_for_directory(&docker_image_extraction, requested_disk_size, filesystem, arguments.disk_headroom)?
+    };
+
+    if !destination_disk.exists() {
+        if filesystem.is_read_only() {
+            // The extracted image is shared with the other disks of the image, so the init is added to a staged copy
+            disk_creator::with_staged_directory(&docker_image_extraction, |staged_dir| {
+                entrypoint::prepare_read_only_root(staged_dir, init_binary.as_ref().unwrap())?;
+                disk_creator::create_from_directory(&destination_disk, disk_size_in_bytes, filesystem, staged_dir)?;
+                Ok::<_, Error>(())
+            })?;
+        } else {
+            disk_creator::create_from_directory(
+                &destination_disk,
+                disk_size_in_bytes,
+                filesystem,
+                &docker_image_extraction
+            )?;
+        }
     }
 
-    fn disconnect(mut conn: Connect) {
-        if let Err(e) = conn.close() {
-            panic!(
-                "Failed to disconnect from hypervisor: code {}, message: {}",
-                e.code, e.message
-            );
+    println!("Creating VM {} ({}) using docker image {} and kernel {}", vm_name, vm_uuid, arguments.docker_image, selected_kernel.version);
+
+    let cow_disk = disks_dir.join(format!("{}.qcow2", vm_uuid));
+    let seed_disk_file = disks_dir.join(format!("{}-seed.iso", vm_uuid));
+
+    // Creates the files and state of the VM and starts it, the files being removed below if any step fails
+    let create_vm = || -> Result<VirtualMachineState, Error> {
+        // A read-only base can't be the backing file of the VM disk, so the VM instead gets an empty disk for the writable layer
+        if filesystem.is_read_only() {
+            disk_creator::create_empty(
+                &cow_disk,
+                disk_size_in_bytes,
+                DiskFormat::Qcow2,
+                &FileSystem::Ext4
+            )?;
+        } else {
+            disk_creator::create_copy_on_write_image(
+                &cow_disk,
+                &destination_disk,
+            )?;
+
+            // The base disk keeps the size of the run that created it, so the overlay is grown to the size of this VM
+            let base_size_in_bytes = DiskInfo::for_disk_file(&cow_disk)?.virtual_size_in_bytes;
+            disk_creator::grow_image(&cow_disk, disk_size_in_bytes.max(base_size_in_bytes), &filesystem)?;
+        }
+        let base_disk = destination_disk;
+        let destination_disk = cow_disk.clone();
+
+        let seed_disk = if arguments.cloud_init.is_some() {
+            let seed = CloudInitSeed {
+                instance_id: vm_uuid.clone(),
+                hostname: arguments.hostname.clone().unwrap_or_else(|| vm_name.clone()),
+                user_data: arguments.cloud_init.as_ref().map(std::fs::read_to_string).transpose()?,
+                ssh_keys: ssh_keys.clone()
+            };
+
+            seed.create_iso(&seed_disk_file)?;
+            Some(seed_disk_file.canonicalize()?)
+        } else {
+            None
+        };
+
+        let mut overlay_setup = OverlaySetup {
+            hostname: arguments.hostname.clone(),
+            root_fstab_entry: if filesystem.is_read_only() {
+                None
+            } else {
+                Some(FstabEntry::for_root(&format!("/dev/{}", definition::ROOT_DISK_DEVICE_ID), &filesystem))
+            },
+            ..Default::default()
+        };
+
+        let shared_folder_driver = SharedFolderDriver::detect();
+        let shared_folders = mounts
+            .iter()
+            .enumerate()
+            .map(|(index, mount)| {
+                SharedFolder {
+                    source_dir: mount.host_path.to_str().unwrap().to_owned(),
+                    tag: format!("mount{}", index),
+                    read_only: mount.read_only
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if !mounts.is_empty() {
+            overlay_setup.fstab_entries = mounts
+                .iter()
+                .zip(&shared_folders)
+                .map(|(mount, shared_folder)| FstabEntry::for_shared_folder(&shared_folder.tag, mount, shared_folder_driver))
+                .collect();
+
+            let module_names = shared_folder_driver.kernel_modules();
+            let module_files = selected_kernel.module_files(module_names)?;
+            if !module_files.is_empty() {
+                overlay_setup.kernel_modules = Some(KernelModules {
+                    kernel_version: selected_kernel.version.clone(),
+                    names: module_names.iter().map(|name| name.to_string()).collect(),
+                    files: module_files
+                });
+            }
+        }
+
+        // The writable layer of a read-only root comes before the volumes
+        let first_volume_index = if filesystem.is_read_only() { 1 } else { 0 };
+        overlay_setup.fstab_entries.extend(
+            volume_mounts
+                .iter()
+                .zip(&volumes)
+                .enumerate()
+                .map(|(index, (volume_mount, volume))| {
+                    let device = format!("/dev/{}", definition::data_disk_device_id(first_volume_index + index));
+                    FstabEntry::for_volume(&device, volume_mount, &volume.filesystem)
+                })
+        );
+
+        let image_config = docker_image::inspect_config(&docker_image)?.with_overrides(&entrypoint_overrides);
+        let entrypoint = Entrypoint::from_image_config(&image_config);
+
+        if !ssh_keys.is_empty() {
+            let user = arguments.ssh_user.clone()
+                .or_else(|| entrypoint.user.as_ref().map(|user| user.split(':').next().unwrap().to_owned()))
+                .unwrap_or_else(|| "root".to_owned());
+
+            // Only the default keys of the host user may be skipped when the user is missing in the image
+            let required = !arguments.ssh_key.is_empty() || arguments.ssh_user.is_some();
+            overlay_setup.authorized_keys = Some(AuthorizedKeys { user, keys: ssh_keys, required });
         }
-        println!("Disconnected from hypervisor");
+
+        if arguments.init || !entrypoint_overrides.is_empty() {
+            match init_binary {
+                Some(init_binary) if arguments.init => {
+                    let hostname = arguments.hostname.clone().unwrap_or_else(|| vm_name.clone());
+                    overlay_setup.init = Some((init_binary, InitConfig { entrypoint, hostname }));
+                }
+                _ => {
+                    overlay_setup.entrypoint = Some(entrypoint);
+                }
+            }
+        }
+
+        let ssh_user = overlay_setup.apply(&base_disk, &destination_disk, &filesystem)?;
+
+        let (root_disk, mut data_disks) = if filesystem.is_read_only() {
+            (
+                Disk::File { filename: base_disk.canonicalize()?.to_str().unwrap().to_owned(), filesystem },
+                vec![Disk::File { filename: destination_disk.canonicalize()?.to_str().unwrap().to_owned(), filesystem: FileSystem::Ext4 }]
+            )
+        } else {
+            (Disk::File { filename: destination_disk.canonicalize()?.to_str().unwrap().to_owned(), filesystem }, Vec::new())
+        };
+        data_disks.extend(
+            volumes
+                .iter()
+                .map(|volume| Disk::File { filename: volume.disk_file.to_str().unwrap().to_owned(), filesystem: volume.filesystem })
+        );
+
+        let vm = VirtualMachine {
+            domain_type: DomainType::for_uri(uri),
+            name: vm_name.clone(),
+            uuid: Some(vm_uuid.clone()),
+            kernel_file: selected_kernel.kernel.clone(),
+            initrd_file: selected_kernel.initrd.clone(),
+            root_disk,
+            data_disks,
+            seed_disk: seed_disk.as_ref().map(|seed_disk| seed_disk.to_str().unwrap().to_owned()),
+            networks: network_interfaces.clone(),
+            shared_folders,
+            shared_folder_driver,
+            headless: arguments.headless,
+            kernel_command_line: KernelCommandLine {
+                full_override: arguments.kernel_cmdline.clone(),
+                target: arguments.target.clone(),
+                init: if arguments.init || filesystem.is_read_only() { Some(entrypoint::INIT_PATH.to_owned()) } else { None },
+                overlay_device: if filesystem.is_read_only() { Some(definition::data_disk_device_id(0)) } else { None },
+                next_init: if filesystem.is_read_only() && !arguments.init { Some("/sbin/init".to_owned()) } else { None },
+                extra_args: arguments.kernel_args.clone(),
+                serial_console: arguments.serial_console || arguments.attach
+            },
+            ram_in_bytes: ram_in_megabytes * 1024 * 1024,
+            num_cpus: arguments.num_cpus,
+            machine: arguments.machine.clone(),
+            uefi_loader: if arguments.bios { None } else { Some(arguments.uefi_loader.clone()) },
+            nvram_directory: hypervisor::nvram_directory(uri)
+        };
+        let vm_definition = vm.get_xml()?;
+
+        let vm_state = VirtualMachineState {
+            name: vm_name,
+            uuid: vm_uuid,
+            image: arguments.docker_image.clone(),
+            image_id,
+            kernel_version: selected_kernel.version.clone(),
+            overlay_disk: destination_disk.canonicalize()?,
+            base_disk: base_disk.canonicalize()?,
+            filesystem,
+            nvram_file: vm.nvram_file(),
+            seed_disk,
+            network_interfaces,
+            published_ports: arguments.publish
+                .iter()
+                .map(|&mapping| PublishedPort { mapping, guest_address: None })
+                .collect(),
+            headless: arguments.headless,
+            init: arguments.init,
+            ssh_user,
+            mounts,
+            volumes: volume_mounts,
+            healthcheck: image_config.healthcheck.as_ref().and_then(HealthCheck::from_config),
+            created: chrono::Utc::now()
+        };
+        state_store.add(&vm_state)?;
+
+        if let Err(err) = hypervisor::with_connection(uri, |conn| hypervisor::create_and_start_vm(conn, &vm_definition)) {
+            remove_vm_files(&vm_state)?;
+            state_store.remove(&vm_state.name)?;
+            return Err(err);
+        }
+
+        Ok(vm_state)
+    };
+
+    // Remove what was created so that the same run can be retried
+    let mut vm_state = match create_vm() {
+        Ok(vm_state) => vm_state,
+        Err(err) => {
+            for file in [&cow_disk, &seed_disk_file] {
+                if file.exists() {
+                    std::fs::remove_file(file)?;
+                }
+            }
+
+            return Err(err);
+        }
+    };
+
+    publish_ports(uri, state_store, &mut vm_state)?;
+
+    if arguments.wait_ip {
+        let address = hypervisor::wait_for_guest_address(
+            uri,
+            &vm_state.name,
+            &vm_state.network_interfaces,
+            Duration::from_secs(arguments.wait_timeout)
+        ).ok_or_else(|| Error::NoGuestAddress(vm_state.name.clone()))?;
+
+        println!("VM {} has the address {}.", vm_state.name, address);
     }
-}
\ No newline at end of file
+
+    let readiness_check = match arguments.wait_port {
+        Some(port) => Some(ReadinessCheck::Port(port)),
+        None if arguments.wait && vm_state.healthcheck.is_some() => Some(ReadinessCheck::HealthCheck),
+        None if arguments.wait => Some(ReadinessCheck::Agent),
+        None => None
+    };
+
+    if let Some(readiness_check) = readiness_check {
+        health::wait_until_ready(uri, &vm_state, readiness_check, Duration::from_secs(arguments.wait_timeout))?;
+        println!("VM {} is ready.", vm_state.name);
+    }
+
+    if arguments.attach {
+        attach(uri, &vm_state.name)?;
+    }
+
+    Ok(())
+}
+
+/// Removes the disk overlay, seed disk and NVRAM of a VM.
+fn remove_vm_files(vm_state: &VirtualMachineState) -> Result<(), Error> {
+    if vm_state.overlay_disk.exists() {
+        std::fs::remove_file(&vm_state.overlay_disk)?;
+    }
+
+    if let Some(seed_disk) = vm_state.seed_disk.as_ref().filter(|seed_disk| seed_disk.exists()) {
+        std::fs::remove_file(seed_disk)?;
+    }
+
+    match std::fs::remove_file(&vm_state.nvram_file) {
+        Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+            helpers::run_command("sudo", ["-S", "rm", "-f", vm_state.nvram_file.to_str().unwrap()])?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn attach(uri: &str, name: &str) -> Result<(), Error> {
+    let result = hypervisor::with_connection(uri, |conn| console::attach(conn, name))?;
+
+    match result {
+        AttachResult::Detached => println!("\r\nDetached from VM {}.", name),
+        AttachResult::Disconnected => println!("\r\nThe console of VM {} was closed.", name)
+    }
+
+    Ok(())
+}
+
+/// Forwards the published ports of a started VM and records the forwarding in its state.
+fn publish_ports(uri: &str, state_store: &StateStore, vm_state: &mut VirtualMachineState) -> Result<(), Error> {
+    vm_state.published_ports = port_forward::publish_ports(uri, vm_state, Duration::from_secs(60))?;
+    state_store.save(vm_state)?;
+    Ok(())
+}
+
+/// Removes the NAT rules of the published ports of a stopped VM, which are added again with its new address on start.
+fn unpublish_ports(state_store: &StateStore, vm_state: &mut VirtualMachineState) -> Result<(), Error> {
+    if vm_state.published_ports.iter().all(|published_port| published_port.guest_address.is_none()) {
+        return Ok(());
+    }
+
+    if let Err(err) = port_forward::unpublish_ports(&vm_state.published_ports) {
+        eprintln!("Failed to remove the port forwarding of VM {}: {}.", vm_state.name, err);
+    }
+
+    for published_port in &mut vm_state.published_ports {
+        published_port.guest_address = None;
+    }
+
+    state_store.save(vm_state)?;
+    Ok(())
+}
+
+fn volume(state_store: &StateStore, volume_store: &VolumeStore, command: VolumeCommand) -> Result<(), Error> {
+    match command {
+        VolumeCommand::Create { name, size, format, filesystem } => {
+            volume_store.create(&name, size * 1024 * 1024, format, filesystem)?;
+            println!("Created volume {}.", name);
+        }
+        VolumeCommand::Ls => {
+            println!("{:<20} {:<8} {:<12} {:<10} {:<20} CREATED", "NAME", "FORMAT", "FILESYSTEM", "SIZE", "USED BY");
+            for volume in volume_store.list()? {
+                println!(
+                    "{:<20} {:<8} {:<12} {:<10} {:<20} {}",
+                    volume.name,
+                    volume.format,
+                    volume.filesystem,
+                    format!("{} MB", volume.size_in_bytes / 1024 / 1024),
+                    volume_user(state_store, &volume.name)?.unwrap_or_default(),
+                    volume.created.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")
+                );
+            }
+        }
+        VolumeCommand::Rm { name } => {
+            volume_store.get(&name)?;
+            if let Some(vm_name) = volume_user(state_store, &name)? {
+                return Err(VolumeError::InUse { name, vm_name }.into());
+            }
+
+            volume_store.remove(&name)?;
+            println!("Removed volume {}.", name);
+        }
+        VolumeCommand::Inspect { name } => {
+            let volume = volume_store.get(&name)?;
+
+            let mut volume_json = serde_json::to_value(&volume).map_err(VolumeError::Serialization)?;
+            volume_json["used_by"] = serde_json::json!(volume_user(state_store, &name)?);
+            println!("{}", serde_json::to_string_pretty(&volume_json).map_err(VolumeError::Serialization)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the VM that the volume is attached to, if any.
+fn volume_user(state_store: &StateStore, volume_name: &str) -> Result<Option<String>, Error> {
+    let vm_state = state_store
+        .list()?
+        .into_iter()
+        .find(|vm_state| vm_state.volumes.iter().any(|volume_mount| volume_mount.name == volume_name));
+
+    Ok(vm_state.map(|vm_state| vm_state.name))
+}
+
+/// The addresses of the VM, which are only known while it is running.
+fn running_vm_addresses(uri: &str, conn: &virt::connect::Connect, vm_state: &VirtualMachineState) -> Vec<String> {
+    if !hypervisor::is_running(conn, &vm_state.name).unwrap_or(false) {
+        return Vec::new();
+    }
+
+    hypervisor::vm_addresses(uri, &vm_state.name, &vm_state.network_interfaces)
+        .into_iter()
+        .map(|address| address.address)
+        .collect()
+}
+
+/// The exit code of the command of a VM booted with --init, which is read from its disk once it has stopped.
+/// This mounts the disk with sudo, so it is only done by inspect and not by listing commands such as ps.
+fn stopped_vm_exit_code(conn: &virt::connect::Connect, vm_state: &VirtualMachineState) -> Option<i32> {
+    if !vm_state.init || hypervisor::is_running(conn, &vm_state.name).unwrap_or(true) {
+        return None;
+    }
+
+    let result = disk_creator::with_mounted_root(&vm_state.base_disk, &vm_state.overlay_disk, &vm_state.filesystem, |root_dir| {
+        let exit_code_file = root_dir.join(entrypoint::EXIT_CODE_PATH.trim_start_matches('/'));
+        Ok::<_, Error>(helpers::run_command("sudo", ["-S", "cat", exit_code_file.to_str().unwrap()]).ok())
+    });
+
+    result.ok().flatten().and_then(|exit_code| exit_code.trim().parse().ok())
+}
+
+/// Gives the network interfaces MAC addresses that no other VM uses, the first one the one given with --mac if any.
+fn network_interfaces(uri: &str,
+                      state_store: &StateStore,
+                      networks: Vec<Network>,
+                      vm_uuid: &uuid::Uuid,
+                      mac: Option<MacAddress>) -> Result<Vec<NetworkInterface>, Error> {
+    if networks.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut used_mac_addresses = hypervisor::with_connection(uri, hypervisor::defined_mac_addresses)?;
+    for vm_state in state_store.list()? {
+        used_mac_addresses.extend(vm_state.network_interfaces.iter().map(|network_interface| network_interface.mac_address));
+    }
+
+    let mut network_interfaces = Vec::new();
+    let mut offset = 0;
+    for (index, network) in networks.into_iter().enumerate() {
+        let mac_address = match mac {
+            Some(mac_address) if index == 0 => {
+                if used_mac_addresses.contains(&mac_address) {
+                    return Err(Error::MacAddressInUse(mac_address));
+                }
+
+                mac_address
+            }
+            _ => {
+                // Derived addresses can clash with those of other VMs, in which case the next one is tried
+                let mut mac_address = MacAddress::from_uuid(vm_uuid, offset);
+                while used_mac_addresses.contains(&mac_address) {
+                    offset += 1;
+                    mac_address = MacAddress::from_uuid(vm_uuid, offset);
+                }
+
+                offset += 1;
+                mac_address
+            }
+        };
+
+        used_mac_addresses.push(mac_address);
+        network_interfaces.push(NetworkInterface { network, mac_address });
+    }
+
+    Ok(network_interfaces)
+}
+
+fn select_networks(uri: &str, arguments: &RunArguments) -> Vec<Network> {
+    if arguments.no_network {
+        return Vec::new();
+    }
+
+    let mut networks = Vec::new();
+    networks.extend(arguments.networks.iter().map(|name| Network::Libvirt(name.clone())));
+    networks.extend(arguments.bridges.iter().map(|name| Network::Bridge(name.clone())));
+    if arguments.user_network {
+        networks.push(Network::User);
+    }
+
+    if networks.is_empty() {
+        // Session mode cannot use the system NAT network, so fall back to user-mode networking
+        if uri.contains("/session") {
+            networks.push(Network::User);
+        } else {
+            networks.push(Network::Libvirt("default".to_owned()));
+        }
+    }
+
+    networks
+}
@@ -0,0 +1,168 @@
+use std::path::PathBuf;
+
+use serde::{Serialize, Deserialize};
+
+use crate::definition::{FileSystem, SharedFolderDriver};
+use crate::volume::VolumeMount;
+
+/// A host directory shared with the VM and mounted at the guest path.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mount {
+    pub host_path: PathBuf,
+    pub guest_path: String,
+    #[serde(default)]
+    pub read_only: bool
+}
+
+/// What to mount in the VM, given to run -v like in docker: a host directory if the source is a path, and otherwise a
+/// named volume.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MountArgument {
+    Bind(Mount),
+    Volume(VolumeMount)
+}
+
+impl std::str::FromStr for MountArgument {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let parts = text.split(':').collect::<Vec<_>>();
+        let (source, guest_path, read_only) = match parts.as_slice() {
+            [source, guest_path] => (*source, guest_path.to_string(), false),
+            [source, guest_path, "ro"] => (*source, guest_path.to_string(), true),
+            [source, guest_path, "rw"] => (*source, guest_path.to_string(), false),
+            _ => return Err(format!("Invalid mount '{}', expected source:guest_path[:ro|rw]", text))
+        };
+
+        if source.is_empty() {
+            return Err(format!("Invalid mount '{}', the source is empty", text));
+        }
+
+        if !guest_path.starts_with('/') || guest_path == "/" {
+            return Err(format!("Invalid mount '{}', the guest path must be an absolute path other than /", text));
+        }
+
+        if source.contains('/') || source.starts_with('.') {
+            Ok(MountArgument::Bind(Mount { host_path: PathBuf::from(source), guest_path, read_only }))
+        } else {
+            Ok(MountArgument::Volume(VolumeMount { name: source.to_owned(), guest_path, read_only }))
+        }
+    }
+}
+
+impl std::fmt::Display for Mount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host_path.display(), self.guest_path)?;
+        if self.read_only {
+            write!(f, ":ro")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A line of /etc/fstab.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FstabEntry {
+    pub device: String,
+    pub mount_point: String,
+    pub file_system: String,
+    pub options: String
+}
+
+impl FstabEntry {
+    /// The entry mounting a shared folder with the given tag. Booting does not fail if the share can't be mounted.
+    pub fn for_shared_folder(tag: &str, mount: &Mount, driver: SharedFolderDriver) -> FstabEntry {
+        let (file_system, options) = driver.mount_options();
+        FstabEntry {
+            device: tag.to_owned(),
+            mount_point: mount.guest_path.clone(),
+            file_system: file_system.to_owned(),
+            options: format!("{},nofail{}", options, if mount.read_only { ",ro" } else { "" })
+        }
+    }
+
+    /// The entry of the root file system, which has systemd grow it to the size of the disk on boot. systemd can only
+    /// grow ext4 and btrfs, so XFS is grown when the disk is (see disk_creator::grow_image).
+    pub fn for_root(device: &str, file_system: &FileSystem) -> FstabEntry {
+        let options = match file_system {
+            FileSystem::Ext4 | FileSystem::Btrfs => "defaults,x-systemd.growfs",
+            _ => "defaults"
+        };
+
+        FstabEntry {
+            device: device.to_owned(),
+            mount_point: "/".to_owned(),
+            file_system: file_system.to_string(),
+            options: options.to_owned()
+        }
+    }
+
+    /// The entry mounting a volume attached as the given device.
+    pub fn for_volume(device: &str, volume_mount: &VolumeMount, file_system: &FileSystem) -> FstabEntry {
+        FstabEntry {
+            device: device.to_owned(),
+            mount_point: volume_mount.guest_path.clone(),
+            file_system: file_system.to_string(),
+            options: format!("defaults,nofail{}", if volume_mount.read_only { ",ro" } else { "" })
+        }
+    }
+}
+
+impl std::fmt::Display for FstabEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Spaces are escaped as octal in fstab
+        write!(f, "{} {} {} {} 0 0", self.device, self.mount_point.replace(' ', "\\040"), self.file_system, self.options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind_mount(text: &str) -> Mount {
+        match text.parse::<MountArgument>().unwrap() {
+            MountArgument::Bind(mount) => mount,
+            MountArgument::Volume(volume_mount) => panic!("Expected a bind mount, got {:?}", volume_mount)
+        }
+    }
+
+    #[test]
+    fn test_parse_mount() {
+        let mount = bind_mount("./src:/work/src:ro");
+        assert_eq!(PathBuf::from("./src"), mount.host_path);
+        assert_eq!("/work/src", mount.guest_path);
+        assert!(mount.read_only);
+
+        assert!(!bind_mount("/data:/data").read_only);
+        assert_eq!(
+            MountArgument::Volume(VolumeMount { name: "pgdata".to_owned(), guest_path: "/var/lib/postgresql/data".to_owned(), read_only: false }),
+            "pgdata:/var/lib/postgresql/data".parse::<MountArgument>().unwrap()
+        );
+
+        assert!("/data:data".parse::<MountArgument>().is_err());
+        assert!("/data:/data:rx".parse::<MountArgument>().is_err());
+        assert!("/data".parse::<MountArgument>().is_err());
+    }
+
+    #[test]
+    fn test_fstab_entry() {
+        let mount = bind_mount("/srv/www:/var/www html:ro");
+        assert_eq!(
+            "mount0 /var/www\\040html virtiofs defaults,nofail,ro 0 0",
+            FstabEntry::for_shared_folder("mount0", &mount, SharedFolderDriver::VirtioFs).to_string()
+        );
+        assert_eq!(
+            "mount1 /var/www\\040html 9p trans=virtio,version=9p2000.L,msize=262144,nofail,ro 0 0",
+            FstabEntry::for_shared_folder("mount1", &mount, SharedFolderDriver::NineP).to_string()
+        );
+
+        let volume_mount = VolumeMount { name: "pgdata".to_owned(), guest_path: "/var/lib/postgresql/data".to_owned(), read_only: false };
+        assert_eq!(
+            "/dev/vdb /var/lib/postgresql/data ext4 defaults,nofail 0 0",
+            FstabEntry::for_volume("/dev/vdb", &volume_mount, &FileSystem::Ext4).to_string()
+        );
+        assert_eq!("/dev/vda / ext4 defaults,x-systemd.growfs 0 0", FstabEntry::for_root("/dev/vda", &FileSystem::Ext4).to_string());
+        assert_eq!("/dev/vda / xfs defaults 0 0", FstabEntry::for_root("/dev/vda", &FileSystem::Xfs).to_string());
+    }
+}
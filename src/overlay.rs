@@ -0,0 +1,225 @@
+use std::path::{Path, PathBuf};
+
+use crate::definition::FileSystem;
+use crate::disk_creator;
+use crate::entrypoint::{self, Entrypoint, InitConfig};
+use crate::error::Error;
+use crate::helpers::run_command;
+use crate::mounts::FstabEntry;
+
+/// Changes made to the disk overlay of a VM before it boots for the first time.
+#[derive(Debug, Default)]
+pub struct OverlaySetup {
+    pub hostname: Option<String>,
+    /// Set when the entrypoint of the image has been changed by the run options.
+    pub entrypoint: Option<Entrypoint>,
+    /// The init binary and its configuration when booting with run --init.
+    pub init: Option<(PathBuf, InitConfig)>,
+    pub authorized_keys: Option<AuthorizedKeys>,
+    pub fstab_entries: Vec<FstabEntry>,
+    /// Added to /etc/fstab unless the image already has an entry for the root file system.
+    pub root_fstab_entry: Option<FstabEntry>,
+    pub kernel_modules: Option<KernelModules>
+}
+
+/// Modules of the host kernel copied to the VM, whose image lacks the modules of the kernel it is booted with.
+#[derive(Debug, Clone)]
+pub struct KernelModules {
+    pub kernel_version: String,
+    pub names: Vec<String>,
+    /// The module files on the host, in the order to load them.
+    pub files: Vec<String>
+}
+
+/// SSH public keys added to the authorized_keys of a user in the VM.
+#[derive(Debug, Clone)]
+pub struct AuthorizedKeys {
+    pub user: String,
+    pub keys: Vec<String>,
+    /// Set when the keys or the user were given explicitly. Otherwise, the keys are added to root when the user
+    /// does not exist in the image, and skipped when neither does.
+    pub required: bool
+}
+
+/// The entry of a user in /etc/passwd.
+#[derive(Debug, Clone, PartialEq)]
+struct PasswdEntry {
+    uid: u32,
+    gid: u32,
+    home: String
+}
+
+impl OverlaySetup {
+    pub fn is_empty(&self) -> bool {
+        self.hostname.is_none() && self.entrypoint.is_none() && self.init.is_none() && self.authorized_keys.is_none()
+            && self.fstab_entries.is_empty() && self.root_fstab_entry.is_none() && self.kernel_modules.is_none()
+    }
+
+    /// Mounts the overlay and applies the changes to it. Returns the user the SSH keys were added to, if any.
+    pub fn apply(&self, base_disk: &Path, overlay_disk: &Path, file_system: &FileSystem) -> Result<Option<String>, Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+
+        disk_creator::with_mounted_root(base_disk, overlay_disk, file_system, |root_dir| self.apply_to(root_dir))
+    }
+
+    fn apply_to(&self, root_dir: &Path) -> Result<Option<String>, Error> {
+        if let Some(hostname) = &self.hostname {
+            entrypoint::install_file(format!("{}\n", hostname).as_bytes(), &root_dir.join("etc/hostname"), "644")?;
+        }
+
+        if let Some((init_binary, init_config)) = &self.init {
+            init_config.install(root_dir, init_binary)?;
+        } else if let Some(entrypoint) = &self.entrypoint {
+            if entrypoint.is_service() {
+                entrypoint.install_systemd_unit(root_dir)?;
+            } else {
+                // The image might have been a service before the command was replaced
+                let service_link = root_dir.join("etc/systemd/system/multi-user.target.wants").join(entrypoint::SERVICE_NAME);
+                run_command("sudo", ["-S", "rm", "-f", service_link.to_str().unwrap()])?;
+
+                // Full systems get the environment variables for login sessions instead
+                let environment_file = root_dir.join("etc/environment");
+                let mut environment = std::fs::read_to_string(&environment_file).unwrap_or_default();
+                if !environment.is_empty() && !environment.ends_with('\n') {
+                    environment.push('\n');
+                }
+
+                for variable in &entrypoint.environment {
+                    environment += &format!("{}\n", variable);
+                }
+
+                entrypoint::install_file(environment.as_bytes(), &environment_file, "644")?;
+            }
+        }
+
+        let ssh_user = match &self.authorized_keys {
+            Some(authorized_keys) => authorized_keys.install(root_dir)?,
+            None => None
+        };
+
+        if let Some(kernel_modules) = &self.kernel_modules {
+            kernel_modules.install(root_dir)?;
+        }
+
+        let fstab_file = root_dir.join("etc/fstab");
+        let mut fstab = std::fs::read_to_string(&fstab_file).unwrap_or_default();
+        let has_root_entry = fstab
+            .lines()
+            .any(|line| !line.trim_start().starts_with('#') && line.split_whitespace().nth(1) == Some("/"));
+
+        let fstab_entries = self.root_fstab_entry
+            .iter()
+            .filter(|_| !has_root_entry)
+            .chain(self.fstab_entries.iter())
+            .collect::<Vec<_>>();
+
+        if !fstab_entries.is_empty() {
+            if !fstab.is_empty() && !fstab.ends_with('\n') {
+                fstab.push('\n');
+            }
+
+            for fstab_entry in fstab_entries {
+                fstab += &format!("{}\n", fstab_entry);
+                let mount_point = root_dir.join(fstab_entry.mount_point.trim_start_matches('/'));
+                run_command("sudo", ["-S", "mkdir", "-p", mount_point.to_str().unwrap()])?;
+            }
+
+            entrypoint::install_file(fstab.as_bytes(), &fstab_file, "644")?;
+        }
+
+        Ok(ssh_user)
+    }
+}
+
+impl AuthorizedKeys {
+    /// Returns the user the keys were added to, which is None if they were skipped.
+    fn install(&self, root_dir: &Path) -> Result<Option<String>, Error> {
+        let passwd = std::fs::read_to_string(root_dir.join("etc/passwd")).unwrap_or_default();
+        let (user, entry) = match find_passwd_entry(&passwd, &self.user) {
+            Some(entry) => (self.user.clone(), entry),
+            None if self.required => return Err(Error::UserNotFound(self.user.clone())),
+            None => match find_passwd_entry(&passwd, "root") {
+                Some(entry) => {
+                    eprintln!("The user {} does not exist in the image, adding the SSH keys to root instead.", self.user);
+                    ("root".to_owned(), entry)
+                }
+                None => {
+                    eprintln!("The image has no user {} or root, not adding any SSH keys.", self.user);
+                    return Ok(None);
+                }
+            }
+        };
+
+        let ssh_dir = root_dir.join(entry.home.trim_start_matches('/')).join(".ssh");
+        let authorized_keys_file = ssh_dir.join("authorized_keys");
+
+        // Keep the keys already in the image, which are only readable by the user
+        let mut authorized_keys = run_command("sudo", ["-S", "cat", authorized_keys_file.to_str().unwrap()]).unwrap_or_default();
+        if !authorized_keys.is_empty() && !authorized_keys.ends_with('\n') {
+            authorized_keys.push('\n');
+        }
+
+        for key in &self.keys {
+            authorized_keys += &format!("{}\n", key);
+        }
+
+        entrypoint::install_file(authorized_keys.as_bytes(), &authorized_keys_file, "600")?;
+        run_command("sudo", ["-S", "chmod", "700", ssh_dir.to_str().unwrap()])?;
+        run_command("sudo", ["-S", "chown", "-R", &format!("{}:{}", entry.uid, entry.gid), ssh_dir.to_str().unwrap()])?;
+        Ok(Some(user))
+    }
+}
+
+impl KernelModules {
+    fn install(&self, root_dir: &Path) -> Result<(), Error> {
+        for file in &self.files {
+            run_command("sudo", ["-S", "install", "-D", "-m", "644", file, root_dir.join(file.trim_start_matches('/')).to_str().unwrap()])?;
+        }
+
+        // The modules.dep and alias files let the kernel load the modules on demand
+        run_command("sudo", ["-S", "depmod", "-b", root_dir.to_str().unwrap(), &self.kernel_version])?;
+
+        // Loaded on boot by systemd-modules-load, and by the init of run --init from the list of files
+        let mut names = self.names.join("\n");
+        names.push('\n');
+        entrypoint::install_file(names.as_bytes(), &root_dir.join("etc/modules-load.d/docker-on-kvm.conf"), "644")?;
+
+        let mut files = self.files.join("\n");
+        files.push('\n');
+        entrypoint::install_file(files.as_bytes(), &root_dir.join(entrypoint::MODULES_PATH.trim_start_matches('/')), "644")?;
+        Ok(())
+    }
+}
+
+/// Finds the entry of the user, given by name or UID, in the content of /etc/passwd.
+fn find_passwd_entry(passwd: &str, user: &str) -> Option<PasswdEntry> {
+    passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .filter(|parts| parts.len() >= 6)
+        .find(|parts| parts[0] == user || parts[2] == user)
+        .and_then(|parts| {
+            Some(PasswdEntry {
+                uid: parts[2].parse().ok()?,
+                gid: parts[3].parse().ok()?,
+                home: parts[5].to_owned()
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_passwd_entry() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\nubuntu:x:1000:1000::/home/ubuntu:/bin/bash\n";
+
+        let entry = PasswdEntry { uid: 1000, gid: 1000, home: "/home/ubuntu".to_owned() };
+        assert_eq!(Some(entry.clone()), find_passwd_entry(passwd, "ubuntu"));
+        assert_eq!(Some(entry), find_passwd_entry(passwd, "1000"));
+        assert_eq!(None, find_passwd_entry(passwd, "postgres"));
+    }
+}
@@ -0,0 +1,287 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use serde::{Serialize, Deserialize};
+
+use crate::definition::{Network, NetworkInterface};
+use crate::error::Error;
+use crate::helpers::{run_command, CommandError};
+use crate::hypervisor;
+use crate::state::VirtualMachineState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp")
+        }
+    }
+}
+
+/// A port on the host forwarded to a port in the VM, given as [ip:]host:guest[/protocol] like docker -p.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    /// The host address to listen on, all addresses if not given.
+    #[serde(default)]
+    pub host_ip: Option<Ipv4Addr>,
+    pub host_port: u16,
+    pub guest_port: u16,
+    pub protocol: Protocol
+}
+
+impl std::str::FromStr for PortMapping {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (ports, protocol) = match text.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some((_, protocol)) => return Err(format!("Unsupported protocol '{}', expected tcp or udp", protocol)),
+            None => (text, Protocol::Tcp)
+        };
+
+        let parts = ports.split(':').collect::<Vec<_>>();
+        let (host_ip, host_port, guest_port) = match parts.as_slice() {
+            [host_port, guest_port] => (None, *host_port, *guest_port),
+            [host_ip, host_port, guest_port] => {
+                let host_ip = host_ip.parse::<Ipv4Addr>().map_err(|_| format!("Invalid host address '{}'", host_ip))?;
+                (Some(host_ip), *host_port, *guest_port)
+            }
+            _ => return Err(format!("Invalid port mapping '{}', expected [ip:]host:guest[/protocol]", text))
+        };
+
+        let parse_port = |port: &str| port.parse::<u16>().map_err(|_| format!("Invalid port '{}'", port));
+        Ok(PortMapping {
+            host_ip,
+            host_port: parse_port(host_port)?,
+            guest_port: parse_port(guest_port)?,
+            protocol
+        })
+    }
+}
+
+impl std::fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(host_ip) = self.host_ip {
+            write!(f, "{}:", host_ip)?;
+        }
+
+        write!(f, "{}:{}/{}", self.host_port, self.guest_port, self.protocol)
+    }
+}
+
+/// A published port of a VM. When forwarded through NAT rules, the guest address the rules point to is recorded so that they can be removed again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishedPort {
+    pub mapping: PortMapping,
+    #[serde(default)]
+    pub guest_address: Option<String>
+}
+
+/// Returns the index of the network interface that published ports are forwarded to. Bridged interfaces are reachable directly and can't be used.
+pub fn forwarding_interface(network_interfaces: &[NetworkInterface]) -> Option<usize> {
+    network_interfaces
+        .iter()
+        .position(|network_interface| !matches!(network_interface.network, Network::Bridge(_)))
+}
+
+/// Checks that ports can be published for a VM with the network interfaces. The NAT rules for libvirt networks are
+/// added with the iptables of this host, so they only work when libvirt runs on it.
+pub fn check_publish(uri: &str, network_interfaces: &[NetworkInterface]) -> Result<(), Error> {
+    let interface_index = forwarding_interface(network_interfaces).ok_or(Error::PublishNotSupported)?;
+    if network_interfaces[interface_index].network != Network::User && !hypervisor::is_local_uri(uri) {
+        return Err(Error::RemotePublishNotSupported);
+    }
+
+    Ok(())
+}
+
+/// Sets up the forwarding of the published ports of a started VM.
+/// For user-mode networking, the forwards live in the QEMU process and must be added every time the VM is started.
+/// For libvirt networks, NAT rules are added once the VM has been leased an address, which is looked up on every start
+/// as it might have changed.
+pub fn publish_ports(uri: &str, vm_state: &VirtualMachineState, timeout: Duration) -> Result<Vec<PublishedPort>, Error> {
+    if vm_state.published_ports.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    check_publish(uri, &vm_state.network_interfaces)?;
+    let interface_index = forwarding_interface(&vm_state.network_interfaces).unwrap();
+    let network_interface = &vm_state.network_interfaces[interface_index];
+
+    // The rules of the previous boot are left if the VM powered itself off, and already removed if it was stopped
+    let _ = unpublish_ports(&vm_state.published_ports);
+
+    let mut published_ports = Vec::new();
+    let mut added_nat_ports = Vec::new();
+    for published_port in &vm_state.published_ports {
+        let mapping = published_port.mapping;
+        let result = match &network_interface.network {
+            Network::User => {
+                add_user_network_forward(uri, &vm_state.name, interface_index, &mapping)
+                    .map(|_| PublishedPort { mapping, guest_address: None })
+                    .map_err(Error::from)
+            }
+            _ => {
+                hypervisor::wait_for_interface_address(uri, &vm_state.name, &network_interface.mac_address.to_string(), timeout)
+                    .map_err(Error::from)
+                    .and_then(|guest_address| guest_address.ok_or_else(|| Error::NoGuestAddress(vm_state.name.clone())))
+                    .and_then(|guest_address| {
+                        add_nat_forward(&mapping, &guest_address)?;
+                        let published_port = PublishedPort { mapping, guest_address: Some(guest_address) };
+                        added_nat_ports.push(published_port.clone());
+                        Ok(published_port)
+                    })
+            }
+        };
+
+        match result {
+            Ok(published_port) => published_ports.push(published_port),
+            Err(err) => {
+                // The rules added so far are not recorded in the state, so they would never be removed
+                if let Err(unpublish_err) = unpublish_ports(&added_nat_ports) {
+                    eprintln!("Failed to remove the port forwarding of VM {}: {}.", vm_state.name, unpublish_err);
+                }
+
+                return Err(err);
+            }
+        }
+
+        println!("Published port {}.", mapping);
+    }
+
+    Ok(published_ports)
+}
+
+/// Removes the NAT rules of the published ports. The user-mode forwards go away together with the QEMU process.
+pub fn unpublish_ports(published_ports: &[PublishedPort]) -> Result<(), CommandError> {
+    for published_port in published_ports {
+        if let Some(guest_address) = &published_port.guest_address {
+            remove_nat_forward(&published_port.mapping, guest_address)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn add_user_network_forward(uri: &str, name: &str, interface_index: usize, mapping: &PortMapping) -> Result<(), CommandError> {
+    // libvirt names the QEMU network backend of the n:th interface hostnet<n>
+    let monitor_command = format!(
+        "hostfwd_add hostnet{} {}:{}:{}-:{}",
+        interface_index,
+        mapping.protocol,
+        mapping.host_ip.map(|host_ip| host_ip.to_string()).unwrap_or_default(),
+        mapping.host_port,
+        mapping.guest_port
+    );
+
+    let output = run_command("virsh", ["-c", uri, "qemu-monitor-command", name, "--hmp", &monitor_command])?;
+
+    // The human monitor reports errors on stdout
+    let output = output.trim();
+    if !output.is_empty() {
+        return Err(CommandError {
+            command: format!("virsh qemu-monitor-command {} --hmp '{}'", name, monitor_command),
+            stderr: output.to_owned(),
+            exit_code: None
+        });
+    }
+
+    Ok(())
+}
+
+fn add_nat_forward(mapping: &PortMapping, guest_address: &str) -> Result<(), CommandError> {
+    let rules = nat_rules(mapping, guest_address);
+    for (index, (table, chain, rule)) in rules.iter().enumerate() {
+        // The FORWARD rule must come before the reject rules of libvirt
+        let action = if *chain == "FORWARD" { "-I" } else { "-A" };
+        let result = run_command("sudo", ["-S", "iptables", "-t", table, action, chain].iter().copied().chain(rule.iter().map(|arg| arg.as_str())));
+
+        // Don't leave a partial forward behind
+        if let Err(err) = result {
+            for (table, chain, rule) in &rules[..index] {
+                let _ = run_command("sudo", ["-S", "iptables", "-t", table, "-D", chain].iter().copied().chain(rule.iter().map(|arg| arg.as_str())));
+            }
+
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_nat_forward(mapping: &PortMapping, guest_address: &str) -> Result<(), CommandError> {
+    // Remove as many of the rules as possible, even if some are already gone
+    let mut result = Ok(());
+    for (table, chain, rule) in nat_rules(mapping, guest_address) {
+        let rule_result = run_command("sudo", ["-S", "iptables", "-t", table, "-D", chain].iter().copied().chain(rule.iter().map(|arg| arg.as_str())));
+        if let (Ok(()), Err(err)) = (&result, rule_result) {
+            result = Err(err);
+        }
+    }
+
+    result
+}
+
+fn nat_rules(mapping: &PortMapping, guest_address: &str) -> Vec<(&'static str, &'static str, Vec<String>)> {
+    let protocol = mapping.protocol.to_string();
+    let host_port = mapping.host_port.to_string();
+    let guest_port = mapping.guest_port.to_string();
+    let destination = format!("{}:{}", guest_address, mapping.guest_port);
+
+    let mut dnat_rule = vec!["-p".to_owned(), protocol.clone(), "--dport".to_owned(), host_port];
+    match mapping.host_ip.filter(|host_ip| !host_ip.is_unspecified()) {
+        Some(host_ip) => dnat_rule.extend(["-d".to_owned(), host_ip.to_string()]),
+        None => dnat_rule.extend(["-m".to_owned(), "addrtype".to_owned(), "--dst-type".to_owned(), "LOCAL".to_owned()])
+    }
+    dnat_rule.extend(["-j".to_owned(), "DNAT".to_owned(), "--to-destination".to_owned(), destination]);
+
+    vec![
+        // Connections from other hosts
+        ("nat", "PREROUTING", dnat_rule.clone()),
+        // Connections from the host itself
+        ("nat", "OUTPUT", dnat_rule),
+        ("filter", "FORWARD", vec![
+            "-p".to_owned(), protocol,
+            "-d".to_owned(), guest_address.to_owned(),
+            "--dport".to_owned(), guest_port,
+            "-j".to_owned(), "ACCEPT".to_owned()
+        ])
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port_mapping() {
+        assert_eq!(
+            PortMapping { host_ip: None, host_port: 8080, guest_port: 80, protocol: Protocol::Tcp },
+            "8080:80".parse::<PortMapping>().unwrap()
+        );
+        assert_eq!(
+            PortMapping { host_ip: Some(Ipv4Addr::LOCALHOST), host_port: 8080, guest_port: 80, protocol: Protocol::Tcp },
+            "127.0.0.1:8080:80/tcp".parse::<PortMapping>().unwrap()
+        );
+        assert_eq!(
+            PortMapping { host_ip: None, host_port: 5353, guest_port: 53, protocol: Protocol::Udp },
+            "5353:53/udp".parse::<PortMapping>().unwrap()
+        );
+        assert_eq!("127.0.0.1:8080:80/tcp", "127.0.0.1:8080:80".parse::<PortMapping>().unwrap().to_string());
+
+        assert!("8080".parse::<PortMapping>().is_err());
+        assert!("8080:80/sctp".parse::<PortMapping>().is_err());
+        assert!("8080:http".parse::<PortMapping>().is_err());
+        assert!("70000:80".parse::<PortMapping>().is_err());
+        assert!("localhost:8080:80".parse::<PortMapping>().is_err());
+        assert!("1:2:3:4".parse::<PortMapping>().is_err());
+    }
+}
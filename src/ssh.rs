@@ -0,0 +1,48 @@
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+
+/// Reads an SSH public key given either as a file or as the key itself.
+pub fn read_ssh_key(ssh_key: &str) -> std::io::Result<String> {
+    if ssh_key.starts_with("ssh-") || ssh_key.starts_with("ecdsa-") || ssh_key.starts_with("sk-") {
+        Ok(ssh_key.to_owned())
+    } else {
+        Ok(std::fs::read_to_string(ssh_key)?.trim().to_owned())
+    }
+}
+
+/// Returns the public keys of the current user, found as ~/.ssh/id_*.pub.
+pub fn default_ssh_keys() -> std::io::Result<Vec<String>> {
+    let ssh_dir = match std::env::var("HOME") {
+        Ok(home) => Path::new(&home).join(".ssh"),
+        Err(_) => return Ok(Vec::new())
+    };
+
+    if !ssh_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut key_files = Vec::new();
+    for entry in std::fs::read_dir(ssh_dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_str().unwrap_or("");
+        if file_name.starts_with("id_") && file_name.ends_with(".pub") {
+            key_files.push(path);
+        }
+    }
+
+    key_files.sort();
+    key_files
+        .iter()
+        .map(|key_file| Ok(std::fs::read_to_string(key_file)?.trim().to_owned()))
+        .collect()
+}
+
+/// Replaces the current process with ssh connected to the given address. The host keys are not checked, as the
+/// addresses are reused by other VMs.
+pub fn connect(address: &str, user: &str, args: &[String]) -> std::io::Error {
+    std::process::Command::new("ssh")
+        .args(["-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null", "-o", "LogLevel=ERROR"])
+        .arg(format!("{}@{}", user, address))
+        .args(args)
+        .exec()
+}
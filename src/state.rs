@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+
+use crate::definition::{FileSystem, NetworkInterface};
+use crate::health::HealthCheck;
+use crate::helpers;
+use crate::mounts::Mount;
+use crate::port_forward::PublishedPort;
+use crate::volume::VolumeMount;
+
+#[derive(Debug)]
+pub enum StateStoreError {
+    IO(std::io::Error),
+    Serialization(serde_json::Error),
+    NotFound(String),
+    AlreadyExists(String),
+    InvalidName(String)
+}
+
+impl std::fmt::Display for StateStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateStoreError::IO(err) => write!(f, "I/O error: {}", err),
+            StateStoreError::Serialization(err) => write!(f, "Failed to (de)serialize VM state: {}", err),
+            StateStoreError::NotFound(name) => write!(f, "No VM named {} exists", name),
+            StateStoreError::AlreadyExists(name) => write!(f, "A VM named {} already exists", name),
+            StateStoreError::InvalidName(name) => write!(f, "Invalid VM name '{}', only letters, digits, '-', '_' and '.' are allowed", name)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualMachineState {
+    pub name: String,
+    pub uuid: String,
+    pub image: String,
+    pub image_id: String,
+    pub kernel_version: String,
+    pub overlay_disk: PathBuf,
+    pub base_disk: PathBuf,
+    /// The file system of the base disk. With a read-only one, the overlay disk holds the writable layer.
+    #[serde(default)]
+    pub filesystem: FileSystem,
+    pub nvram_file: PathBuf,
+    #[serde(default)]
+    pub seed_disk: Option<PathBuf>,
+    #[serde(default)]
+    pub network_interfaces: Vec<NetworkInterface>,
+    #[serde(default)]
+    pub published_ports: Vec<PublishedPort>,
+    #[serde(default)]
+    pub headless: bool,
+    /// Booted with the init of run --init, which records the exit code of the command.
+    #[serde(default)]
+    pub init: bool,
+    #[serde(default)]
+    pub ssh_user: Option<String>,
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    #[serde(default)]
+    pub volumes: Vec<VolumeMount>,
+    #[serde(default)]
+    pub healthcheck: Option<HealthCheck>,
+    pub created: DateTime<Utc>
+}
+
+pub struct StateStore {
+    root: PathBuf
+}
+
+impl StateStore {
+    pub fn new(root: &Path) -> StateStore {
+        StateStore {
+            root: root.to_owned()
+        }
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.state_file(name).exists()
+    }
+
+    pub fn add(&self, state: &VirtualMachineState) -> Result<(), StateStoreError> {
+        if !helpers::is_valid_name(&state.name) {
+            return Err(StateStoreError::InvalidName(state.name.clone()));
+        }
+
+        if self.exists(&state.name) {
+            return Err(StateStoreError::AlreadyExists(state.name.clone()));
+        }
+
+        self.save(state)
+    }
+
+    pub fn save(&self, state: &VirtualMachineState) -> Result<(), StateStoreError> {
+        if !self.root.exists() {
+            std::fs::create_dir_all(&self.root).map_err(StateStoreError::IO)?;
+        }
+
+        let content = serde_json::to_string_pretty(state).map_err(StateStoreError::Serialization)?;
+        std::fs::write(self.state_file(&state.name), content).map_err(StateStoreError::IO)?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<VirtualMachineState, StateStoreError> {
+        let state_file = self.state_file(name);
+        if !state_file.exists() {
+            return Err(StateStoreError::NotFound(name.to_owned()));
+        }
+
+        let content = std::fs::read_to_string(state_file).map_err(StateStoreError::IO)?;
+        serde_json::from_str(&content).map_err(StateStoreError::Serialization)
+    }
+
+    pub fn list(&self) -> Result<Vec<VirtualMachineState>, StateStoreError> {
+        let mut states = Vec::new();
+        if !self.root.exists() {
+            return Ok(states);
+        }
+
+        for entry in std::fs::read_dir(&self.root).map_err(StateStoreError::IO)? {
+            let path = entry.map_err(StateStoreError::IO)?.path();
+            if path.extension().map(|extension| extension == "json").unwrap_or(false) {
+                let content = std::fs::read_to_string(&path).map_err(StateStoreError::IO)?;
+                states.push(serde_json::from_str(&content).map_err(StateStoreError::Serialization)?);
+            }
+        }
+
+        states.sort_by_key(|state: &VirtualMachineState| state.created);
+        Ok(states)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), StateStoreError> {
+        let state_file = self.state_file(name);
+        if !state_file.exists() {
+            return Err(StateStoreError::NotFound(name.to_owned()));
+        }
+
+        std::fs::remove_file(state_file).map_err(StateStoreError::IO)
+    }
+
+    fn state_file(&self, name: &str) -> PathBuf {
+        self.root.join(format!("{}.json", name))
+    }
+}
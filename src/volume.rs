@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+
+use crate::definition::FileSystem;
+use crate::disk_creator::{self, DiskCreateError, DiskFormat};
+use crate::helpers;
+
+#[derive(Debug)]
+pub enum VolumeError {
+    IO(std::io::Error),
+    Serialization(serde_json::Error),
+    DiskCreate(DiskCreateError),
+    InvalidName(String),
+    NotFound(String),
+    AlreadyExists(String),
+    InUse { name: String, vm_name: String }
+}
+
+impl std::fmt::Display for VolumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VolumeError::IO(err) => write!(f, "I/O error: {}", err),
+            VolumeError::Serialization(err) => write!(f, "Failed to (de)serialize volume: {}", err),
+            VolumeError::DiskCreate(err) => write!(f, "{}", err),
+            VolumeError::InvalidName(name) => write!(f, "Invalid volume name '{}', only letters, digits, '-', '_' and '.' are allowed", name),
+            VolumeError::NotFound(name) => write!(f, "No volume named {} exists", name),
+            VolumeError::AlreadyExists(name) => write!(f, "A volume named {} already exists", name),
+            VolumeError::InUse { name, vm_name } => write!(f, "The volume {} is used by VM {}", name, vm_name)
+        }
+    }
+}
+
+/// A named data disk that lives independently of the VMs using it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Volume {
+    pub name: String,
+    pub disk_file: PathBuf,
+    pub format: DiskFormat,
+    pub filesystem: FileSystem,
+    pub size_in_bytes: u64,
+    pub created: DateTime<Utc>
+}
+
+/// A volume attached to a VM, given to run as name:guest_path[:ro|rw].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VolumeMount {
+    pub name: String,
+    pub guest_path: String,
+    #[serde(default)]
+    pub read_only: bool
+}
+
+/// Stores the volumes as disk images together with a JSON file describing them.
+pub struct VolumeStore {
+    root: PathBuf
+}
+
+impl VolumeStore {
+    pub fn new(root: &Path) -> VolumeStore {
+        VolumeStore {
+            root: root.to_owned()
+        }
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.volume_file(name).exists()
+    }
+
+    pub fn create(&self,
+                  name: &str,
+                  size_in_bytes: u64,
+                  format: DiskFormat,
+                  filesystem: FileSystem) -> Result<Volume, VolumeError> {
+        if !helpers::is_valid_name(name) {
+            return Err(VolumeError::InvalidName(name.to_owned()));
+        }
+
+        if self.exists(name) {
+            return Err(VolumeError::AlreadyExists(name.to_owned()));
+        }
+
+        if !self.root.exists() {
+            std::fs::create_dir_all(&self.root).map_err(VolumeError::IO)?;
+        }
+
+        let disk_file = self.root.join(format!("{}.{}", name, format));
+        disk_creator::create_empty(&disk_file, size_in_bytes, format, &filesystem).map_err(VolumeError::DiskCreate)?;
+
+        let volume = Volume {
+            name: name.to_owned(),
+            disk_file: disk_file.canonicalize().map_err(VolumeError::IO)?,
+            format,
+            filesystem,
+            size_in_bytes,
+            created: Utc::now()
+        };
+
+        let content = serde_json::to_string_pretty(&volume).map_err(VolumeError::Serialization)?;
+        std::fs::write(self.volume_file(name), content).map_err(VolumeError::IO)?;
+        Ok(volume)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Volume, VolumeError> {
+        let volume_file = self.volume_file(name);
+        if !volume_file.exists() {
+            return Err(VolumeError::NotFound(name.to_owned()));
+        }
+
+        let content = std::fs::read_to_string(volume_file).map_err(VolumeError::IO)?;
+        serde_json::from_str(&content).map_err(VolumeError::Serialization)
+    }
+
+    pub fn list(&self) -> Result<Vec<Volume>, VolumeError> {
+        let mut volumes = Vec::new();
+        if !self.root.exists() {
+            return Ok(volumes);
+        }
+
+        for entry in std::fs::read_dir(&self.root).map_err(VolumeError::IO)? {
+            let path = entry.map_err(VolumeError::IO)?.path();
+            if path.extension().map(|extension| extension == "json").unwrap_or(false) {
+                let content = std::fs::read_to_string(&path).map_err(VolumeError::IO)?;
+                volumes.push(serde_json::from_str(&content).map_err(VolumeError::Serialization)?);
+            }
+        }
+
+        volumes.sort_by(|a: &Volume, b: &Volume| a.name.cmp(&b.name));
+        Ok(volumes)
+    }
+
+    /// Removes the volume together with its disk image.
+    pub fn remove(&self, name: &str) -> Result<(), VolumeError> {
+        let volume = self.get(name)?;
+        if volume.disk_file.exists() {
+            std::fs::remove_file(&volume.disk_file).map_err(VolumeError::IO)?;
+        }
+
+        std::fs::remove_file(self.volume_file(name)).map_err(VolumeError::IO)
+    }
+
+    fn volume_file(&self, name: &str) -> PathBuf {
+        self.root.join(format!("{}.json", name))
+    }
+}